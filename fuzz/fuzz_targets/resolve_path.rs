@@ -0,0 +1,55 @@
+#![no_main]
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/resolve.rs"]
+#[allow(dead_code)]
+mod resolve;
+
+use resolve::{resolve_file, sanitize_path, SymlinkPolicy};
+
+// A small pages directory with a symlink inside it and one pointing out of it, created once per run
+fn pages_dir() -> &'static PathBuf {
+    static PAGES_DIR: OnceLock<PathBuf> = OnceLock::new();
+    PAGES_DIR.get_or_init(|| {
+        let base = std::env::temp_dir().join(format!("geser-fuzz-{}", std::process::id()));
+        let root = base.join("pages");
+        std::fs::create_dir_all(root.join("docs/.hidden")).unwrap();
+        std::fs::write(root.join("docs/page.md"), "page").unwrap();
+        std::fs::write(base.join("secret.md"), "secret").unwrap();
+        #[cfg(unix)]
+        {
+            let _ = std::os::unix::fs::symlink(root.join("docs"), root.join("alias"));
+            let _ = std::os::unix::fs::symlink(&base, root.join("escape"));
+        }
+        root.canonicalize().unwrap()
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    // Request paths reach the resolver as text, after URL parsing
+    let Ok(path) = std::str::from_utf8(data) else { return };
+
+    for allow_hidden in [false, true] {
+        let Ok(safe_path) = sanitize_path(path, allow_hidden) else { continue };
+        assert!(!safe_path.contains(['\0', '\\']), "{:?} kept a NUL byte or backslash", path);
+        assert!(!safe_path.chars().any(char::is_control), "{:?} kept a control character", path);
+        assert!(!Path::new(&safe_path).components().any(|c| c == Component::ParentDir), "{:?} kept a parent directory", path);
+        if !allow_hidden {
+            assert!(
+                safe_path.split('/').all(|c| !c.starts_with('.') || c == "." || c == ".well-known"),
+                "{:?} kept a hidden component",
+                path
+            );
+        }
+
+        let root = pages_dir();
+        for policy in [SymlinkPolicy::Never, SymlinkPolicy::Inside] {
+            if let Ok(resolved) = resolve_file(root, &safe_path, policy) {
+                assert!(resolved.starts_with(root), "{:?} resolved to {} outside the pages directory", path, resolved.display());
+            }
+        }
+    }
+});
@@ -0,0 +1,284 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow};
+
+use crate::config::AccessRule;
+use crate::pages;
+use crate::resolve::{resolve_file, SymlinkPolicy};
+
+/// Name of the per-directory access rule files in pages_dir.
+pub const ACCESS_FILE: &str = ".geser-access";
+
+/// Outcome of checking a request against the access rules.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// The client's address is denied, or not in the allowed ranges.
+    AddressDenied,
+    /// A client certificate is required but none was presented.
+    CertificateRequired,
+    /// The client certificate is not one of the allowed fingerprints or users.
+    CertificateNotAuthorised,
+}
+
+impl Verdict {
+    /// The Gemini response refusing the request, or `None` if it is allowed.
+    pub fn response(&self) -> Option<&'static str> {
+        match self {
+            Verdict::Allow => None,
+            Verdict::AddressDenied => Some("53 Access denied\r\n"),
+            Verdict::CertificateRequired => Some("60 Client certificate required\r\n"),
+            Verdict::CertificateNotAuthorised => Some("61 Certificate not authorised\r\n"),
+        }
+    }
+
+    /// Why the request is refused, for protocols without Gemini status codes, or `None` if it is allowed.
+    pub fn reason(&self) -> Option<&'static str> {
+        match self {
+            Verdict::Allow => None,
+            Verdict::AddressDenied => Some("Access denied"),
+            Verdict::CertificateRequired => Some("Client certificate required"),
+            Verdict::CertificateNotAuthorised => Some("Certificate not authorised"),
+        }
+    }
+}
+
+/// Checks whether a rule's path prefix covers a request path, matching whole path segments only.
+pub fn applies_to(rule: &AccessRule, path: &str) -> bool {
+    let prefix = rule.prefix.trim_end_matches('/');
+    path == prefix || path.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Checks a request against one rule: addresses first, then the client certificate and the
+/// username it is registered under. A client without a known address (on the Unix socket, with
+/// no PROXY header naming it) is refused by any rule that lists addresses.
+pub fn evaluate(rule: &AccessRule, ip: Option<IpAddr>, fingerprint: Option<&str>, user: Option<&str>) -> Verdict {
+    let address_denied = match ip.map(|ip| ip.to_canonical()) {
+        Some(ip) => rule.deny.iter().any(|net| net.contains(&ip)) || (!rule.allow.is_empty() && !rule.allow.iter().any(|net| net.contains(&ip))),
+        None => !rule.allow.is_empty() || !rule.deny.is_empty(),
+    };
+    if address_denied {
+        return Verdict::AddressDenied;
+    }
+    let Some(fingerprint) = fingerprint else {
+        let needs_cert = rule.require_cert || !rule.fingerprints.is_empty() || !rule.users.is_empty();
+        return if needs_cert { Verdict::CertificateRequired } else { Verdict::Allow };
+    };
+    let fingerprint_allowed = rule.fingerprints.is_empty() || rule.fingerprints.iter().any(|f| f.eq_ignore_ascii_case(fingerprint));
+    let user_allowed = rule.users.is_empty() || user.is_some_and(|user| rule.users.iter().any(|u| u == user));
+    if fingerprint_allowed && user_allowed {
+        Verdict::Allow
+    } else {
+        Verdict::CertificateNotAuthorised
+    }
+}
+
+/// Checks a sanitized request path against the rules that apply to it (see `applicable_rules`).
+/// Every one of them must allow the request. Fails if an access file cannot be read or parsed,
+/// so a broken file never opens up a directory. Protocols without client certificates pass no
+/// fingerprint or user, so any rule asking for a certificate refuses them.
+pub async fn check(rules: &[AccessRule], pages_dir: &str, safe_path: &str, symlinks: SymlinkPolicy, ip: Option<IpAddr>, fingerprint: Option<&str>, user: Option<&str>) -> Result<Verdict> {
+    let (rules, root, path) = (rules.to_vec(), pages_dir.to_string(), safe_path.to_string());
+    let applicable = tokio::task::spawn_blocking(move || applicable_rules(&rules, &root, &path, symlinks)).await??;
+    for rule in &applicable {
+        let verdict = evaluate(rule, ip, fingerprint, user);
+        if verdict != Verdict::Allow {
+            tracing::info!("Refused {} for {}: {:?} by rule for {}", safe_path, ip.map_or("unknown address".to_string(), |ip| ip.to_string()), verdict, rule.prefix);
+            return Ok(verdict);
+        }
+    }
+    Ok(Verdict::Allow)
+}
+
+/// The `[[access]]` rules from the configuration and the `.geser-access` files in the directories
+/// along a sanitized request path, from pages_dir down. When symlinks lead the request elsewhere
+/// inside pages_dir, the rules for the path it resolves to apply as well, so a link cannot bypass
+/// the rules of the directory it points into. Reads files, so it blocks.
+pub fn applicable_rules(rules: &[AccessRule], pages_dir: &str, safe_path: &str, symlinks: SymlinkPolicy) -> Result<Vec<AccessRule>> {
+    let mut paths = vec![safe_path.to_string()];
+    paths.extend(resolved_path(pages_dir, safe_path, symlinks).filter(|resolved| resolved != safe_path));
+    let mut applicable = Vec::new();
+    for path in &paths {
+        applicable.extend(rules.iter().filter(|rule| applies_to(rule, path)).cloned());
+        applicable.extend(load_access_files(pages_dir, path)?);
+    }
+    Ok(applicable)
+}
+
+/// Checks whether a rule refuses anyone at all, as opposed to one that sets no restrictions.
+pub fn restricts(rule: &AccessRule) -> bool {
+    !rule.allow.is_empty() || !rule.deny.is_empty() || rule.require_cert || !rule.fingerprints.is_empty() || !rule.users.is_empty()
+}
+
+// The path inside pages_dir that a request resolves to under the symlink policy, written like a
+// request path: the page its file serves, or else the path with its deepest existing directory
+// resolved, which covers directories and files yet to be uploaded. `None` if it resolves to
+// nothing inside pages_dir.
+fn resolved_path(pages_dir: &str, safe_path: &str, symlinks: SymlinkPolicy) -> Option<String> {
+    let root = Path::new(pages_dir).canonicalize().ok()?;
+    let (resolved, is_page) = match resolve_file(Path::new(pages_dir), &pages::page_file(safe_path), symlinks) {
+        Ok(file) => (file, true),
+        Err(_) => (resolve_existing(Path::new(pages_dir), safe_path, symlinks)?, false),
+    };
+    let relative = resolved.strip_prefix(&root).ok()?;
+    let path = format!("/{}", relative.to_string_lossy());
+    if !is_page || pages::is_static_asset(safe_path) {
+        return Some(path);
+    }
+    // Markdown files are mapped back to the page they serve
+    Some(match path.strip_suffix("index.md").filter(|directory| directory.ends_with('/')) {
+        Some(directory) => directory.to_string(),
+        None => path.strip_suffix(".md").map_or(path.clone(), str::to_string),
+    })
+}
+
+// Resolves the longest part of a path that exists and appends the rest as it is
+fn resolve_existing(root: &Path, relative: &str, symlinks: SymlinkPolicy) -> Option<PathBuf> {
+    let components: Vec<&str> = relative.split('/').filter(|component| !component.is_empty()).collect();
+    for existing in (0..=components.len()).rev() {
+        match resolve_file(root, &components[..existing].join("/"), symlinks) {
+            Ok(mut path) => {
+                path.extend(&components[existing..]);
+                return Some(path);
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(_) => return None,
+        }
+    }
+    None
+}
+
+// Reads the access files in pages_dir and each directory named by the path, giving each rule its directory as prefix
+fn load_access_files(pages_dir: &str, safe_path: &str) -> Result<Vec<AccessRule>> {
+    let mut rules = Vec::new();
+    let mut directory = Path::new(pages_dir).to_path_buf();
+    let mut prefix = String::from("/");
+    let mut segments = safe_path.split('/').filter(|segment| !segment.is_empty());
+    loop {
+        let file = directory.join(ACCESS_FILE);
+        match std::fs::read_to_string(&file) {
+            Ok(content) => {
+                let mut rule: AccessRule = config::Config::builder()
+                    .add_source(config::File::from_str(&content, config::FileFormat::Toml))
+                    .build()
+                    .and_then(config::Config::try_deserialize)
+                    .map_err(|e| anyhow!("Invalid access file {}: {}", file.display(), e))?;
+                rule.prefix = prefix.clone();
+                rules.push(rule);
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound || e.kind() == std::io::ErrorKind::NotADirectory => {},
+            Err(e) => return Err(anyhow!("Failed to read access file {}: {}", file.display(), e)),
+        }
+        let Some(segment) = segments.next() else { break };
+        directory.push(segment);
+        prefix = format!("{}{}/", prefix, segment);
+    }
+    Ok(rules)
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+
+    fn rule(prefix: &str) -> AccessRule {
+        AccessRule { prefix: prefix.to_string(), ..AccessRule::default() }
+    }
+
+    // Test that prefixes match whole path segments
+    #[test]
+    fn test_applies_to() {
+        let members = rule("/members/");
+        assert!(applies_to(&members, "/members"));
+        assert!(applies_to(&members, "/members/page"));
+        assert!(!applies_to(&members, "/membership"));
+        assert!(applies_to(&rule("/"), "/anything"));
+    }
+
+    // Test address and certificate checks
+    #[test]
+    fn test_evaluate() {
+        let local: Option<IpAddr> = "10.0.0.5".parse().ok();
+        let remote: Option<IpAddr> = "192.0.2.1".parse().ok();
+
+        let mut addresses = rule("/");
+        addresses.allow = vec!["10.0.0.0/8".parse().unwrap()];
+        addresses.deny = vec!["10.0.0.5/32".parse().unwrap()];
+        assert_eq!(evaluate(&addresses, remote, None, None), Verdict::AddressDenied, "Addresses outside allow are refused");
+        assert_eq!(evaluate(&addresses, local, None, None), Verdict::AddressDenied, "deny wins over allow");
+        assert_eq!(evaluate(&addresses, "10.0.0.6".parse().ok(), None, None), Verdict::Allow);
+        assert_eq!(evaluate(&addresses, "::ffff:10.0.0.6".parse().ok(), None, None), Verdict::Allow);
+        assert_eq!(evaluate(&addresses, None, None, None), Verdict::AddressDenied, "Unknown addresses are refused by allow lists");
+        addresses.allow.clear();
+        assert_eq!(evaluate(&addresses, None, None, None), Verdict::AddressDenied, "Unknown addresses are refused by deny lists");
+        assert_eq!(evaluate(&rule("/"), None, None, None), Verdict::Allow, "Rules without addresses do not need one");
+
+        let mut certificates = rule("/");
+        certificates.require_cert = true;
+        assert_eq!(evaluate(&certificates, remote, None, None), Verdict::CertificateRequired);
+        assert_eq!(evaluate(&certificates, remote, Some("ab"), None), Verdict::Allow);
+        certificates.fingerprints = vec!["AB".to_string()];
+        assert_eq!(evaluate(&certificates, remote, Some("ab"), None), Verdict::Allow);
+        assert_eq!(evaluate(&certificates, remote, Some("cd"), None), Verdict::CertificateNotAuthorised);
+
+        let mut users = rule("/");
+        users.users = vec!["alice".to_string()];
+        assert_eq!(evaluate(&users, remote, None, None), Verdict::CertificateRequired);
+        assert_eq!(evaluate(&users, remote, Some("ab"), None), Verdict::CertificateNotAuthorised, "Unregistered certificates are refused");
+        assert_eq!(evaluate(&users, remote, Some("ab"), Some("bob")), Verdict::CertificateNotAuthorised);
+        assert_eq!(evaluate(&users, remote, Some("ab"), Some("alice")), Verdict::Allow);
+        assert_eq!(Verdict::CertificateNotAuthorised.response(), Some("61 Certificate not authorised\r\n"));
+    }
+
+    // Test combining configured rules with access files along the path
+    #[tokio::test]
+    async fn test_check_access_files() {
+        let pages_dir = std::env::temp_dir().join(format!("geser-access-{}", std::process::id()));
+        std::fs::create_dir_all(pages_dir.join("members/private")).unwrap();
+        std::fs::write(pages_dir.join("members").join(ACCESS_FILE), "require_cert = true\n").unwrap();
+        std::fs::write(pages_dir.join("members/private").join(ACCESS_FILE), "fingerprints = [\"ab\"]\n").unwrap();
+        let pages_dir_str = pages_dir.to_string_lossy().to_string();
+        let ip: Option<IpAddr> = "192.0.2.1".parse().ok();
+
+        assert_eq!(check(&[], &pages_dir_str, "/index", SymlinkPolicy::Inside, ip, None, None).await.unwrap(), Verdict::Allow);
+        assert_eq!(check(&[], &pages_dir_str, "/members", SymlinkPolicy::Inside, ip, None, None).await.unwrap(), Verdict::CertificateRequired, "The directory's own path is covered");
+        assert_eq!(check(&[], &pages_dir_str, "/members/", SymlinkPolicy::Inside, ip, None, None).await.unwrap(), Verdict::CertificateRequired);
+        assert_eq!(check(&[], &pages_dir_str, "/members/page", SymlinkPolicy::Inside, ip, Some("cd"), None).await.unwrap(), Verdict::Allow);
+        assert_eq!(check(&[], &pages_dir_str, "/members/private/page", SymlinkPolicy::Inside, ip, Some("cd"), None).await.unwrap(), Verdict::CertificateNotAuthorised);
+        assert_eq!(check(&[], &pages_dir_str, "/members/private/page", SymlinkPolicy::Inside, ip, Some("ab"), None).await.unwrap(), Verdict::Allow);
+
+        let mut denied = rule("/members");
+        denied.deny = vec!["192.0.2.0/24".parse().unwrap()];
+        assert_eq!(check(&[denied], &pages_dir_str, "/members/page", SymlinkPolicy::Inside, ip, Some("ab"), None).await.unwrap(), Verdict::AddressDenied);
+
+        std::fs::write(pages_dir.join("members").join(ACCESS_FILE), "require_cert = maybe\n").unwrap();
+        assert!(check(&[], &pages_dir_str, "/members/page", SymlinkPolicy::Inside, ip, Some("ab"), None).await.is_err(), "Broken access files refuse access");
+        std::fs::write(pages_dir.join("members").join(ACCESS_FILE), "require_certificate = true\n").unwrap();
+        assert!(check(&[], &pages_dir_str, "/members/page", SymlinkPolicy::Inside, ip, Some("ab"), None).await.is_err(), "Misspelt keys are not ignored");
+        std::fs::remove_dir_all(&pages_dir).unwrap();
+    }
+
+    // Test that symlinks into a protected directory are held to its rules
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_check_symlinks() {
+        let pages_dir = std::env::temp_dir().join(format!("geser-access-links-{}", std::process::id()));
+        std::fs::create_dir_all(pages_dir.join("members/club")).unwrap();
+        std::fs::create_dir_all(pages_dir.join("public")).unwrap();
+        std::fs::write(pages_dir.join("members").join(ACCESS_FILE), "require_cert = true\n").unwrap();
+        std::fs::write(pages_dir.join("members/club/index.md"), "# Club").unwrap();
+        std::fs::write(pages_dir.join("members/club/news.md"), "# News").unwrap();
+        std::os::unix::fs::symlink(pages_dir.join("members/club"), pages_dir.join("public/alias")).unwrap();
+        std::os::unix::fs::symlink(pages_dir.join("members/club/news.md"), pages_dir.join("public/news.md")).unwrap();
+        let pages_dir_str = pages_dir.to_string_lossy().to_string();
+        let ip: Option<IpAddr> = "192.0.2.1".parse().ok();
+
+        for path in ["/public/alias/news", "/public/alias/", "/public/alias", "/public/news"] {
+            assert_eq!(check(&[], &pages_dir_str, path, SymlinkPolicy::Inside, ip, None, None).await.unwrap(), Verdict::CertificateRequired, "{} leads into /members", path);
+        }
+        let mut denied = rule("/members/club/news");
+        denied.deny = vec!["192.0.2.0/24".parse().unwrap()];
+        assert_eq!(check(&[denied], &pages_dir_str, "/public/alias/news", SymlinkPolicy::Inside, ip, Some("ab"), None).await.unwrap(), Verdict::AddressDenied, "Configured rules apply to the resolved page");
+        std::fs::remove_dir_all(&pages_dir).unwrap();
+    }
+}
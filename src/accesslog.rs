@@ -0,0 +1,373 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use anyhow::{Result, Context};
+use serde::Serialize;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use tokio::io::AsyncWrite;
+
+use crate::config::{AccessLogFormat, AccessLogSettings, Rotation};
+
+/// One served request, as written to the access log.
+#[derive(Debug, Serialize)]
+pub struct AccessEntry {
+    pub timestamp: String,
+    /// The client's address, unknown for Unix socket clients unless a PROXY header names them.
+    pub ip: Option<String>,
+    pub host: Option<String>,
+    pub path: String,
+    /// Two-digit status code of the response, if one was sent.
+    pub status: Option<String>,
+    pub bytes: u64,
+    pub duration_ms: u128,
+    /// SHA-256 fingerprint of the client certificate, if one was presented.
+    pub client_cert: Option<String>,
+    /// Username the client certificate is registered under.
+    pub user: Option<String>,
+}
+
+impl AccessEntry {
+    pub fn new(ip: Option<IpAddr>, host: Option<&str>, path: &str, status: Option<&str>, bytes: u64, duration: Duration, client_cert: Option<&str>) -> Self {
+        let now = OffsetDateTime::now_utc();
+        AccessEntry {
+            timestamp: now.format(&Rfc3339).unwrap_or_else(|_| now.to_string()),
+            ip: ip.map(|ip| ip.to_string()),
+            host: host.map(str::to_string),
+            path: path.to_string(),
+            status: status.map(str::to_string),
+            bytes,
+            duration_ms: duration.as_millis(),
+            client_cert: client_cert.map(str::to_string),
+            user: None,
+        }
+    }
+
+    /// Adds the registered username of the client.
+    pub fn with_user(mut self, user: Option<&str>) -> Self {
+        self.user = user.map(str::to_string);
+        self
+    }
+
+    /// Formats the entry as a single line, without the trailing newline.
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Plain => format!(
+                "{} {} {} {} {} {} {}ms {} {}",
+                self.timestamp,
+                self.ip.as_deref().unwrap_or("-"),
+                self.host.as_deref().unwrap_or("-"),
+                if self.path.is_empty() { "/" } else { &self.path },
+                self.status.as_deref().unwrap_or("-"),
+                self.bytes,
+                self.duration_ms,
+                self.client_cert.as_deref().unwrap_or("-"),
+                self.user.as_deref().unwrap_or("-")
+            ),
+            AccessLogFormat::Json => serde_json::to_string(self).unwrap_or_default(),
+        }
+    }
+}
+
+/// Truncates an address for logging: IPv4 to its /24 and IPv6 to its /48.
+pub fn anonymize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            IpAddr::from([a, b, c, 0])
+        },
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            IpAddr::from([segments[0], segments[1], segments[2], 0, 0, 0, 0, 0])
+        },
+    }
+}
+
+/// The file being written and what is needed to decide when to rotate it.
+struct LogFile {
+    file: File,
+    size: u64,
+    date: time::Date,
+}
+
+/// What the writer thread is asked to do.
+enum Message {
+    Line(String),
+    /// Reply once every line sent before has been written.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Writes access log entries to stdout or a file, rotating the file by size or date. Lines are
+/// handed to a dedicated writer thread, so that slow disks and rotation never block connections.
+#[derive(Clone)]
+pub struct AccessLog {
+    settings: Arc<AccessLogSettings>,
+    sender: Option<mpsc::Sender<Message>>,
+}
+
+impl AccessLog {
+    // Opens the access log configured in the settings and starts its writer thread
+    pub fn open(settings: &AccessLogSettings) -> Result<Self> {
+        if !settings.enabled {
+            return Ok(AccessLog { settings: Arc::new(settings.clone()), sender: None });
+        }
+        let file = match &settings.path {
+            Some(path) => Some(open_log_file(path)?),
+            None => None,
+        };
+        let (sender, receiver) = mpsc::channel();
+        let writer = LogWriter { settings: settings.clone(), file };
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || writer.run(receiver))
+            .context("Failed to start the access log writer")?;
+        Ok(AccessLog {
+            settings: Arc::new(settings.clone()),
+            sender: Some(sender),
+        })
+    }
+
+    // Queues one entry for writing, anonymizing the address if configured
+    pub fn record(&self, mut entry: AccessEntry) {
+        let Some(sender) = &self.sender else { return };
+        if self.settings.anonymize_ip {
+            if let Some(ip) = entry.ip.as_deref().and_then(|ip| ip.parse::<IpAddr>().ok()) {
+                entry.ip = Some(anonymize_ip(ip).to_string());
+            }
+        }
+        let _ = sender.send(Message::Line(entry.format(self.settings.format)));
+    }
+
+    /// Blocks until every entry recorded so far has been written.
+    pub fn flush(&self) {
+        let Some(sender) = &self.sender else { return };
+        let (done, wait) = mpsc::channel();
+        if sender.send(Message::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+/// Owns the log file on the writer thread.
+struct LogWriter {
+    settings: AccessLogSettings,
+    file: Option<LogFile>,
+}
+
+impl LogWriter {
+    // Writes lines until every AccessLog handle has been dropped
+    fn run(mut self, receiver: mpsc::Receiver<Message>) {
+        for message in receiver {
+            match message {
+                Message::Line(line) => self.write(&line),
+                Message::Flush(done) => { let _ = done.send(()); },
+            }
+        }
+    }
+
+    fn write(&mut self, line: &str) {
+        let Some(path) = self.settings.path.clone() else {
+            // A closed stdout must not take the server down
+            let _ = writeln!(std::io::stdout().lock(), "{}", line);
+            return;
+        };
+        if let Err(e) = self.write_line(&path, &format!("{}\n", line)) {
+            tracing::error!("Failed to write access log {}: {:?}", path, e);
+        }
+    }
+
+    fn write_line(&mut self, path: &str, line: &str) -> Result<()> {
+        let today = OffsetDateTime::now_utc().date();
+        let rotate = match (self.settings.rotation, self.file.as_ref()) {
+            (Rotation::Size, Some(log)) => log.size > 0 && log.size + line.len() as u64 > self.settings.max_size,
+            (Rotation::Daily, Some(log)) => log.date != today,
+            _ => false,
+        };
+        if rotate {
+            let date = self.file.as_ref().map_or(today, |log| log.date);
+            self.file = None;
+            rotate_log_file(path, &self.settings, date)?;
+        }
+        if self.file.is_none() {
+            self.file = Some(open_log_file(path)?);
+        }
+        let Some(log) = self.file.as_mut() else { return Ok(()) };
+        log.file.write_all(line.as_bytes())?;
+        log.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+// Opens the log file for appending, noting its size and the date it was last written
+fn open_log_file(path: &str) -> Result<LogFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)
+        .with_context(|| format!("Failed to open access log {}", path))?;
+    let metadata = file.metadata()?;
+    let date = metadata.modified().map(OffsetDateTime::from).unwrap_or_else(|_| OffsetDateTime::now_utc()).date();
+    Ok(LogFile { file, size: metadata.len(), date })
+}
+
+// Moves the current log aside. Size rotation shifts numbered files (`access.log.1` is the newest);
+// daily rotation names the file after the day it covers. Only the newest `keep` files are kept.
+fn rotate_log_file(path: &str, settings: &AccessLogSettings, date: time::Date) -> Result<()> {
+    match settings.rotation {
+        Rotation::Size => {
+            for index in (1..settings.keep).rev() {
+                let from = format!("{}.{}", path, index);
+                if Path::new(&from).exists() {
+                    std::fs::rename(&from, format!("{}.{}", path, index + 1))?;
+                }
+            }
+            if settings.keep == 0 {
+                std::fs::remove_file(path)?;
+            } else {
+                std::fs::rename(path, format!("{}.1", path))?;
+            }
+        },
+        Rotation::Daily => {
+            std::fs::rename(path, format!("{}.{}", path, date))?;
+            let log_path = Path::new(path);
+            let directory = log_path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            let prefix = format!("{}.", log_path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default());
+            let mut rotated: Vec<_> = std::fs::read_dir(directory)?
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+                .map(|entry| entry.path())
+                .collect();
+            // Dated names sort chronologically, so the oldest come first
+            rotated.sort();
+            let excess = rotated.len().saturating_sub(settings.keep);
+            for old in &rotated[..excess] {
+                std::fs::remove_file(old)?;
+            }
+        },
+        Rotation::Never => {},
+    }
+    Ok(())
+}
+
+/// Wraps the connection's writer to record the status code and number of bytes of the response.
+pub struct ResponseRecorder<W> {
+    inner: W,
+    bytes: u64,
+    header: Vec<u8>,
+}
+
+impl<W> ResponseRecorder<W> {
+    pub fn new(inner: W) -> Self {
+        ResponseRecorder { inner, bytes: 0, header: Vec::new() }
+    }
+
+    /// Bytes written so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The two-digit status code, once it has been written.
+    pub fn status(&self) -> Option<&str> {
+        std::str::from_utf8(self.header.get(..2)?).ok()
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ResponseRecorder<W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.bytes += written as u64;
+            let missing = 2usize.saturating_sub(self.header.len()).min(written);
+            self.header.extend_from_slice(&buf[..missing]);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+    use tokio::io::AsyncWriteExt;
+
+    fn entry() -> AccessEntry {
+        let mut entry = AccessEntry::new(
+            "192.0.2.7".parse().ok(),
+            Some("example.org"),
+            "/about",
+            Some("20"),
+            120,
+            Duration::from_millis(15),
+            None,
+        );
+        entry.timestamp = "2026-01-02T03:04:05Z".to_string();
+        entry
+    }
+
+    // Test the plain and JSON line formats
+    #[test]
+    fn test_format_entry() {
+        assert_eq!(
+            entry().format(AccessLogFormat::Plain),
+            "2026-01-02T03:04:05Z 192.0.2.7 example.org /about 20 120 15ms - -"
+        );
+        assert!(entry().with_user(Some("alice")).format(AccessLogFormat::Plain).ends_with(" - alice"));
+        let json: serde_json::Value = serde_json::from_str(&entry().format(AccessLogFormat::Json)).unwrap();
+        assert_eq!(json["status"], "20");
+        assert_eq!(json["client_cert"], serde_json::Value::Null);
+        assert_eq!(json["user"], serde_json::Value::Null);
+        assert_eq!(anonymize_ip("192.0.2.7".parse().unwrap()).to_string(), "192.0.2.0");
+        assert_eq!(anonymize_ip("2001:db8:1:2::7".parse().unwrap()).to_string(), "2001:db8:1::");
+    }
+
+    // Test writing to a file and rotating it by size
+    #[test]
+    fn test_size_rotation() {
+        let dir = std::env::temp_dir().join(format!("geser-access-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log").to_string_lossy().to_string();
+        let settings = AccessLogSettings {
+            enabled: true,
+            path: Some(path.clone()),
+            anonymize_ip: true,
+            rotation: Rotation::Size,
+            max_size: 100,
+            keep: 2,
+            ..AccessLogSettings::default()
+        };
+        let log = AccessLog::open(&settings).unwrap();
+        for _ in 0..5 {
+            log.record(entry());
+        }
+        log.flush();
+
+        let current = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(current.lines().count(), 1, "Each file holds as many lines as fit in max_size");
+        assert!(current.contains(" 192.0.2.0 "), "Addresses should be anonymized");
+        assert!(Path::new(&format!("{}.1", path)).exists());
+        assert!(Path::new(&format!("{}.2", path)).exists());
+        assert!(!Path::new(&format!("{}.3", path)).exists(), "Only `keep` rotated files are kept");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Test recording the status and size of a response
+    #[tokio::test]
+    async fn test_response_recorder() {
+        let mut recorder = ResponseRecorder::new(Vec::new());
+        assert_eq!(recorder.status(), None);
+        recorder.write_all(b"2").await.unwrap();
+        recorder.write_all(b"0 text/gemini\r\nHello").await.unwrap();
+        assert_eq!(recorder.status(), Some("20"));
+        assert_eq!(recorder.bytes(), 21);
+    }
+}
@@ -0,0 +1,169 @@
+use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+
+use crate::cache::Cache;
+use crate::certinfo::CertInfo;
+use crate::config::{AdminSettings, Settings};
+use crate::metrics::{self, METRICS};
+
+/// Server state shown and acted on by the admin page.
+#[derive(Clone)]
+pub struct AdminContext {
+    started: Instant,
+    tls_reload: mpsc::UnboundedSender<()>,
+    /// The certificate being served, which the file at cert_path may no longer match.
+    certificate: watch::Receiver<Option<CertInfo>>,
+}
+
+impl AdminContext {
+    // Creates the context for a server started now, requesting TLS reloads through `tls_reload`
+    pub fn new(tls_reload: mpsc::UnboundedSender<()>, certificate: watch::Receiver<Option<CertInfo>>) -> Self {
+        AdminContext {
+            started: Instant::now(),
+            tls_reload,
+            certificate,
+        }
+    }
+}
+
+/// Checks whether a sanitized request path falls under the admin page.
+pub fn is_admin_path(settings: &AdminSettings, path: &str) -> bool {
+    let base = settings.path.trim_end_matches('/');
+    settings.enabled && (path == base || path.strip_prefix(base).is_some_and(|rest| rest.starts_with('/')))
+}
+
+/// Handles a request under the admin page and returns the complete response.
+/// Clients without a certificate get `60`, clients whose certificate is not an admin's get `61`.
+pub fn handle_request(path: &str, fingerprint: Option<&str>, settings: &Settings, cache: &Cache, context: &AdminContext) -> String {
+    let Some(fingerprint) = fingerprint else {
+        return "60 Client certificate required\r\n".to_string();
+    };
+    if !settings.admin.fingerprints.iter().any(|f| f.eq_ignore_ascii_case(fingerprint)) {
+        tracing::warn!("Refused admin request for {} from certificate {}", path, fingerprint);
+        return "61 Certificate not authorised\r\n".to_string();
+    }
+
+    let base = settings.admin.path.trim_end_matches('/');
+    match path.strip_prefix(base).unwrap_or_default().trim_end_matches('/') {
+        "" => format!("20 text/gemini\r\n{}", status_page(settings, cache, context)),
+        "/flush-cache" => {
+            let entries = cache.len();
+            cache.clear();
+            tracing::info!("Cache flushed from the admin page ({} entries removed)", entries);
+            format!("30 {}\r\n", base)
+        },
+        "/reload-tls" => {
+            tracing::info!("TLS reload requested from the admin page");
+            if context.tls_reload.send(()).is_err() {
+                return "40 TLS reload task is not running\r\n".to_string();
+            }
+            format!("30 {}\r\n", base)
+        },
+        _ => "51 Not Found\r\n".to_string(),
+    }
+}
+
+// Renders the status page as gemtext
+fn status_page(settings: &Settings, cache: &Cache, context: &AdminContext) -> String {
+    let base = settings.admin.path.trim_end_matches('/');
+    let mut page = String::from("# Geser status\n\n");
+    let _ = writeln!(page, "Version: {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(page, "Uptime: {}", format_uptime(context.started.elapsed()));
+    let _ = writeln!(page, "Active connections: {}", metrics::get(&METRICS.active_connections));
+
+    let requests: Vec<(usize, u64)> = METRICS.requests.iter().enumerate()
+        .map(|(code, counter)| (code, metrics::get(counter)))
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    let _ = writeln!(page, "\n## Requests\n\nTotal: {}", requests.iter().map(|(_, count)| count).sum::<u64>());
+    for (code, count) in requests {
+        let status = if code == 0 { "no response".to_string() } else { format!("{:02}", code) };
+        let _ = writeln!(page, "* {}: {}", status, count);
+    }
+    let _ = writeln!(page, "Bytes sent: {}", metrics::get(&METRICS.response_bytes));
+
+    let _ = writeln!(page, "\n## Cache\n");
+    let _ = writeln!(page, "Entries: {}", cache.len());
+    let _ = writeln!(page, "Size: {} bytes", cache.size_bytes());
+    let _ = writeln!(page, "Hits: {}", metrics::get(&METRICS.cache_hits));
+    let _ = writeln!(page, "Misses: {}", metrics::get(&METRICS.cache_misses));
+
+    let _ = writeln!(page, "\n## Certificate\n");
+    let report = context.certificate.borrow().as_ref()
+        .map(CertInfo::report)
+        .unwrap_or_else(|| format!("Failed to read {}", settings.cert_path));
+    let _ = writeln!(page, "```\n{}\n```", report);
+    let _ = writeln!(
+        page,
+        "TLS reloads: {} succeeded, {} failed",
+        metrics::get(&METRICS.tls_reloads),
+        metrics::get(&METRICS.tls_reload_failures)
+    );
+
+    let _ = writeln!(page, "\n## Actions\n");
+    let _ = writeln!(page, "=> {}/flush-cache Flush the page cache", base);
+    let _ = writeln!(page, "=> {}/reload-tls Reload the TLS certificate", base);
+    page
+}
+
+// Formats a duration as days, hours, minutes and seconds
+fn format_uptime(uptime: Duration) -> String {
+    let seconds = uptime.as_secs();
+    format!("{}d {}h {}m {}s", seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60, seconds % 60)
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+
+    fn settings() -> Settings {
+        Settings {
+            cert_path: "test_cert.pem".to_string(),
+            admin: AdminSettings {
+                enabled: true,
+                fingerprints: vec!["ABCDEF".to_string()],
+                ..AdminSettings::default()
+            },
+            ..Settings::default()
+        }
+    }
+
+    // Test which paths belong to the admin page
+    #[test]
+    fn test_is_admin_path() {
+        let settings = settings();
+        assert!(is_admin_path(&settings.admin, "/.well-known/geser/status"));
+        assert!(is_admin_path(&settings.admin, "/.well-known/geser/status/reload-tls"));
+        assert!(!is_admin_path(&settings.admin, "/.well-known/geser/statuses"));
+        assert!(!is_admin_path(&settings.admin, "/"));
+        assert!(!is_admin_path(&AdminSettings::default(), "/.well-known/geser/status"), "The page is disabled by default");
+    }
+
+    // Test certificate checks, the status page and the actions
+    #[test]
+    fn test_admin_requests() {
+        let settings = settings();
+        let cache = Cache::new();
+        let (tls_reload, mut requested) = mpsc::unbounded_channel();
+        let context = AdminContext::new(tls_reload, watch::channel(crate::tls::leaf_info(&settings.cert_path)).1);
+        let path = "/.well-known/geser/status";
+
+        assert!(handle_request(path, None, &settings, &cache, &context).starts_with("60 "));
+        assert!(handle_request(path, Some("123456"), &settings, &cache, &context).starts_with("61 "));
+
+        cache.set_text("/index.md".to_string(), "hello".to_string());
+        let page = handle_request(path, Some("abcdef"), &settings, &cache, &context);
+        assert!(page.starts_with("20 text/gemini\r\n# Geser status"));
+        assert!(page.contains("Entries: 1"));
+        assert!(page.contains("SHA-256 fingerprint: "));
+        assert!(page.contains("=> /.well-known/geser/status/flush-cache "));
+
+        assert_eq!(handle_request(&format!("{}/flush-cache", path), Some("abcdef"), &settings, &cache, &context), format!("30 {}\r\n", path));
+        assert_eq!(cache.len(), 0, "Flushing empties the cache");
+        assert_eq!(handle_request(&format!("{}/reload-tls", path), Some("abcdef"), &settings, &cache, &context), format!("30 {}\r\n", path));
+        assert!(requested.try_recv().is_ok(), "A TLS reload should be requested");
+        assert!(handle_request(&format!("{}/other", path), Some("abcdef"), &settings, &cache, &context).starts_with("51 "));
+    }
+}
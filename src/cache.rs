@@ -1,5 +1,6 @@
 use dashmap::DashMap;
 use std::sync::Arc;
+use crate::metrics::{self, METRICS};
 
 #[derive(Clone)]
 pub struct Cache {
@@ -18,7 +19,7 @@ impl Cache {
 
     // Gets a cached text value by key
     pub fn get_text(&self, key: &str) -> Option<String> {
-        self.text_cache.get(key).map(|v| v.value().clone())
+        count_lookup(self.text_cache.get(key).map(|v| v.value().clone()))
     }
 
     // Sets a text value in the cache with a specified key
@@ -28,13 +29,43 @@ impl Cache {
 
     // Gets a cached binary value by key
     pub fn get_binary(&self, key: &str) -> Option<Vec<u8>> {
-        self.binary_cache.get(key).map(|v| v.value().clone())
+        count_lookup(self.binary_cache.get(key).map(|v| v.value().clone()))
     }
 
     // Sets a binary value in the cache with a specified key
     pub fn set_binary(&self, key: String, value: Vec<u8>) {
         self.binary_cache.insert(key, value);
     }
+
+    // Removes any cached text or binary value stored under the key
+    pub fn invalidate(&self, key: &str) {
+        self.text_cache.remove(key);
+        self.binary_cache.remove(key);
+    }
+
+    // Removes every cached value
+    pub fn clear(&self) {
+        self.text_cache.clear();
+        self.binary_cache.clear();
+    }
+
+    // Number of cached text and binary values
+    pub fn len(&self) -> usize {
+        self.text_cache.len() + self.binary_cache.len()
+    }
+
+    // Total size of the cached values in bytes
+    pub fn size_bytes(&self) -> usize {
+        self.text_cache.iter().map(|entry| entry.value().len()).sum::<usize>()
+            + self.binary_cache.iter().map(|entry| entry.value().len()).sum::<usize>()
+    }
+}
+
+// Counts a cache lookup as a hit or a miss
+fn count_lookup<T>(value: Option<T>) -> Option<T> {
+    let counter = if value.is_some() { &METRICS.cache_hits } else { &METRICS.cache_misses };
+    metrics::increment(counter);
+    value
 }
 
 // Test module
@@ -63,4 +94,29 @@ mod tests {
         assert_eq!(cache.get_binary("key1"), Some(vec![1, 2, 3, 4]));
         assert_eq!(cache.get_binary("key2"), None); // Key "key2" doesn't exist
     }
+
+    // Test cache invalidation
+    #[test]
+    fn test_invalidate() {
+        let cache = Cache::new();
+        cache.set_text("key1".to_string(), "value1".to_string());
+        cache.set_binary("key1".to_string(), vec![1, 2, 3]);
+        cache.invalidate("key1");
+
+        // Both caches should no longer hold the key
+        assert_eq!(cache.get_text("key1"), None);
+        assert_eq!(cache.get_binary("key1"), None);
+    }
+
+    // Test the entry count and size used by the metrics
+    #[test]
+    fn test_cache_size() {
+        let cache = Cache::new();
+        cache.set_text("key1".to_string(), "value1".to_string());
+        cache.set_binary("key2".to_string(), vec![1, 2, 3]);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.size_bytes(), 9);
+        cache.clear();
+        assert_eq!(cache.len(), 0);
+    }
 }
@@ -0,0 +1,166 @@
+use std::net::IpAddr;
+use anyhow::{Result, anyhow};
+use rustls::Certificate;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+use x509_parser::oid_registry::{OID_KEY_TYPE_EC_PUBLIC_KEY, OID_PKCS1_RSAENCRYPTION, OID_SIG_ED25519};
+
+use crate::tls::cert_fingerprint;
+
+/// Number of days before expiry at which loading a certificate starts logging warnings.
+pub const EXPIRY_WARNING_DAYS: i64 = 30;
+
+/// Details of a parsed X.509 certificate, as logged on load and printed by `check-tls`.
+#[derive(Debug, Clone)]
+pub struct CertInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub sans: Vec<String>,
+    pub not_before: OffsetDateTime,
+    pub not_after: OffsetDateTime,
+    pub fingerprint: String,
+    pub key_type: String,
+}
+
+impl CertInfo {
+    /// Parses a DER-encoded certificate.
+    pub fn parse(cert: &Certificate) -> Result<Self> {
+        let (_, x509) = X509Certificate::from_der(&cert.0)
+            .map_err(|e| anyhow!("Failed to parse certificate: {}", e))?;
+
+        let mut sans = Vec::new();
+        if let Ok(Some(extension)) = x509.subject_alternative_name() {
+            for name in &extension.value.general_names {
+                match name {
+                    GeneralName::DNSName(dns) => sans.push(format!("DNS:{}", dns)),
+                    GeneralName::IPAddress(bytes) => {
+                        let ip = match bytes.len() {
+                            4 => <[u8; 4]>::try_from(*bytes).ok().map(IpAddr::from),
+                            16 => <[u8; 16]>::try_from(*bytes).ok().map(IpAddr::from),
+                            _ => None,
+                        };
+                        if let Some(ip) = ip {
+                            sans.push(format!("IP:{}", ip));
+                        }
+                    },
+                    _ => {}
+                }
+            }
+        }
+
+        let spki = x509.public_key();
+        let algorithm = &spki.algorithm.algorithm;
+        let key_size = spki.parsed().map(|key| key.key_size()).unwrap_or(0);
+        let key_type = if *algorithm == OID_PKCS1_RSAENCRYPTION {
+            format!("RSA {} bits", key_size)
+        } else if *algorithm == OID_KEY_TYPE_EC_PUBLIC_KEY {
+            format!("ECDSA P-{}", key_size)
+        } else if *algorithm == OID_SIG_ED25519 {
+            "Ed25519".to_string()
+        } else {
+            format!("unknown ({})", algorithm)
+        };
+
+        Ok(CertInfo {
+            subject: x509.subject().to_string(),
+            issuer: x509.issuer().to_string(),
+            sans,
+            not_before: x509.validity().not_before.to_datetime(),
+            not_after: x509.validity().not_after.to_datetime(),
+            fingerprint: cert_fingerprint(cert),
+            key_type,
+        })
+    }
+
+    /// Whole days until the certificate expires; negative once it has expired.
+    pub fn days_until_expiry(&self) -> i64 {
+        (self.not_after - OffsetDateTime::now_utc()).whole_days()
+    }
+
+    /// A human-readable multi-line report of the certificate details.
+    pub fn report(&self) -> String {
+        let format = |time: OffsetDateTime| time.format(&Rfc3339).unwrap_or_else(|_| time.to_string());
+        format!(
+            "Subject: {}\nIssuer: {}\nSubject alternative names: {}\nValid from: {}\nValid until: {} ({} days left)\nKey type: {}\nSHA-256 fingerprint: {}",
+            self.subject,
+            self.issuer,
+            if self.sans.is_empty() { "none".to_string() } else { self.sans.join(", ") },
+            format(self.not_before),
+            format(self.not_after),
+            self.days_until_expiry(),
+            self.key_type,
+            self.fingerprint
+        )
+    }
+
+    /// Logs the certificate details, warning as expiry approaches and erroring once it has passed.
+    pub fn log(&self, cert_path: &str) {
+        tracing::info!("Loaded certificate {}:\n{}", cert_path, self.report());
+        self.log_expiry(cert_path);
+        if self.not_before > OffsetDateTime::now_utc() {
+            tracing::warn!("Certificate {} is not valid until {}", cert_path, self.not_before);
+        }
+    }
+
+    /// Logs a warning as expiry approaches and an error once it has passed; silent otherwise.
+    /// Repeated daily while serving, so an expiring certificate is noticed without a reload.
+    pub fn log_expiry(&self, cert_path: &str) {
+        match self.expiry_warning(cert_path) {
+            Some(warning) if self.not_after < OffsetDateTime::now_utc() => tracing::error!("{}", warning),
+            Some(warning) => tracing::warn!("{}", warning),
+            None => {}
+        }
+    }
+
+    // The message to log about the certificate's expiry, if it expires within EXPIRY_WARNING_DAYS
+    fn expiry_warning(&self, cert_path: &str) -> Option<String> {
+        let days = self.days_until_expiry();
+        if self.not_after < OffsetDateTime::now_utc() {
+            Some(format!("Certificate {} expired on {}", cert_path, self.not_after))
+        } else if days < EXPIRY_WARNING_DAYS {
+            Some(format!("Certificate {} expires in {} days, on {}", cert_path, days, self.not_after))
+        } else {
+            None
+        }
+    }
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+
+    // Test parsing a generated certificate
+    #[test]
+    fn test_parse_cert_info() {
+        let mut params = rcgen::CertificateParams::new(vec!["example.org".to_string()]);
+        params.subject_alt_names.push(rcgen::SanType::IpAddress("127.0.0.1".parse().unwrap()));
+        params.not_after = OffsetDateTime::now_utc() + time::Duration::days(10);
+        let cert = Certificate(rcgen::Certificate::from_params(params).unwrap().serialize_der().unwrap());
+
+        let info = CertInfo::parse(&cert).unwrap();
+        assert_eq!(info.sans, vec!["DNS:example.org".to_string(), "IP:127.0.0.1".to_string()]);
+        assert_eq!(info.key_type, "ECDSA P-256");
+        assert_eq!(info.fingerprint, cert_fingerprint(&cert));
+        assert!((9..=10).contains(&info.days_until_expiry()));
+        assert!(info.report().contains("Key type: ECDSA P-256"));
+        assert!(CertInfo::parse(&Certificate(b"not a certificate".to_vec())).is_err());
+    }
+
+    // Test the expiry warnings logged on load and every day while serving
+    #[test]
+    fn test_expiry_warning() {
+        let info = |days: i64| {
+            let mut params = rcgen::CertificateParams::new(vec!["example.org".to_string()]);
+            params.not_before = OffsetDateTime::now_utc() - time::Duration::days(400);
+            params.not_after = OffsetDateTime::now_utc() + time::Duration::days(days);
+            CertInfo::parse(&Certificate(rcgen::Certificate::from_params(params).unwrap().serialize_der().unwrap())).unwrap()
+        };
+        assert!(info(365).expiry_warning("cert.pem").is_none());
+        assert!(info(10).expiry_warning("cert.pem").unwrap().starts_with("Certificate cert.pem expires in 9 days"));
+        assert!(info(-2).expiry_warning("cert.pem").unwrap().starts_with("Certificate cert.pem expired on"));
+    }
+}
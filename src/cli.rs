@@ -0,0 +1,81 @@
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use tracing::Level;
+
+/// Command-line interface: global options plus one subcommand (`serve` when none is given).
+#[derive(Debug, Parser)]
+#[command(name = "geser", version, about = "A Gemini server that serves Markdown pages")]
+pub struct Cli {
+    /// Configuration file to load instead of `config.toml` in the current directory
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<String>,
+
+    /// Override the listening address from the configuration
+    #[arg(long, global = true)]
+    pub address: Option<String>,
+
+    /// Override the pages directory from the configuration
+    #[arg(long, global = true, value_name = "DIR")]
+    pub pages_dir: Option<String>,
+
+    /// Log level: error, warn, info, debug or trace
+    #[arg(long, global = true, default_value = "info")]
+    pub log_level: Level,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the server (the default)
+    Serve,
+    /// Load and validate the configuration, then exit
+    CheckConfig,
+    /// Print certificate details (subject, SANs, validity, fingerprint, key type) and check the key matches
+    CheckTls,
+    /// Generate a self-signed certificate at cert_path/key_path
+    GenCert {
+        /// Overwrite an existing certificate and key
+        #[arg(long)]
+        force: bool,
+    },
+    /// Export the capsule as a static gemtext tree
+    Build {
+        /// Directory to write the gemtext tree to
+        #[arg(default_value = "public")]
+        output_dir: PathBuf,
+        /// Also write an HTML version of the site to this directory
+        #[arg(long, value_name = "DIR")]
+        html: Option<PathBuf>,
+    },
+    /// Print the version and exit
+    Version,
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+
+    // Test parsing of global options and subcommands
+    #[test]
+    fn test_parse_cli() {
+        let cli = Cli::try_parse_from(["geser", "--config", "/etc/geser/a.toml", "build", "out", "--html", "site", "--log-level", "debug"]).unwrap();
+        assert_eq!(cli.config.as_deref(), Some("/etc/geser/a.toml"));
+        assert_eq!(cli.log_level, Level::DEBUG);
+        match cli.command {
+            Some(Command::Build { output_dir, html }) => {
+                assert_eq!(output_dir, PathBuf::from("out"));
+                assert_eq!(html, Some(PathBuf::from("site")));
+            },
+            other => panic!("Unexpected command: {:?}", other),
+        }
+
+        let cli = Cli::try_parse_from(["geser", "--address", "[::]:1965", "--pages-dir", "capsule"]).unwrap();
+        assert!(cli.command.is_none(), "No subcommand means serve");
+        assert_eq!(cli.address.as_deref(), Some("[::]:1965"));
+        assert_eq!(cli.pages_dir.as_deref(), Some("capsule"));
+        assert!(Cli::try_parse_from(["geser", "--log-level", "loud"]).is_err());
+    }
+}
@@ -1,24 +1,746 @@
+use std::path::Path;
+use std::sync::Arc;
 use serde::Deserialize;
-use anyhow::Result;
+use ipnet::IpNet;
+use anyhow::{Result, anyhow};
+use tokio::sync::{mpsc, watch};
+use crate::metrics::{self, METRICS};
+use crate::resolve::SymlinkPolicy;
+use crate::util::{watch_files, Hangup};
 
-#[derive(Debug, Deserialize)]
+/// Settings shared with the listeners; replaced as a whole when the configuration is reloaded.
+pub type SharedSettings = watch::Receiver<Arc<Settings>>;
+
+/// Where the settings were loaded from, so that they can be loaded again on reload.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSource {
+    /// Configuration file given on the command line; `config` in the current directory otherwise.
+    pub path: Option<String>,
+    /// Command-line overrides as (key, value) pairs, applied on top of the file and environment.
+    pub overrides: Vec<(String, String)>,
+}
+
+impl ConfigSource {
+    // Creates a new Settings instance by loading configuration from a file, environment variables and overrides.
+    // The file is the given config path, or an optional "config" file in the current directory.
+    pub fn load(&self) -> Result<Settings> {
+        let file = match &self.path {
+            Some(path) => config::File::with_name(path).required(true),  // An explicitly given file must exist
+            None => config::File::with_name("config").required(false),  // Optionally load config from "config" file
+        };
+        let mut builder = config::Config::builder()
+            .add_source(file)
+            .add_source(config::Environment::with_prefix("GEMINI").separator("_")); // Load configuration from environment variables with "GEMINI" prefix
+        for (key, value) in &self.overrides {
+            builder = builder.set_override(key.as_str(), value.as_str())?;
+        }
+
+        let mut settings = builder.build()?.try_deserialize::<Settings>()?; // Deserialize config into Settings struct
+        settings.source = self.clone();
+        Ok(settings)
+    }
+
+    // The file to watch for changes
+    fn watched_file(&self) -> String {
+        match &self.path {
+            Some(path) if !Path::new(path).exists() && Path::new(&format!("{}.toml", path)).exists() => format!("{}.toml", path),
+            Some(path) => path.clone(),
+            None => "config.toml".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub struct Settings {
     pub address: String,
+    /// Further addresses the Gemini listener binds to, in addition to `address`.
+    #[serde(default)]
+    pub listen: Vec<String>,
+    /// Binds IPv6 addresses with IPV6_V6ONLY, so that `[::]` does not also accept IPv4
+    /// and `0.0.0.0` can be bound alongside it.
+    #[serde(default)]
+    pub ipv6_only: bool,
+    /// Path of a Unix domain socket the Gemini listener accepts connections on as well.
+    #[serde(default)]
+    pub unix_socket: Option<String>,
     pub cert_path: String,
     pub key_path: String,
     pub pages_dir: String,
     pub tls_reload_interval_secs: u64,
+    /// How long to wait for in-flight connections to finish when shutting down.
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// Name of an environment variable holding the passphrase of an encrypted PKCS#8 key.
+    #[serde(default)]
+    pub key_passphrase_env: Option<String>,
+    /// File holding the passphrase of an encrypted PKCS#8 key (trailing newlines are ignored).
+    #[serde(default)]
+    pub key_passphrase_file: Option<String>,
+    /// User to switch to once the listeners are bound and the TLS material is loaded.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Group to switch to; the user's primary group if not set.
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Confine the server to `pages_dir` with chroot before switching user.
+    #[serde(default)]
+    pub chroot: bool,
+    #[serde(default)]
+    pub self_signed: SelfSignedSettings,
+    /// Hostnames the capsule is served under, e.g. `["example.org"]`.
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+    #[serde(default)]
+    pub titan: TitanSettings,
+    #[serde(default)]
+    pub spartan: SpartanSettings,
+    #[serde(default)]
+    pub gopher: GopherSettings,
+    #[serde(default)]
+    pub http: HttpSettings,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    #[serde(default)]
+    pub limits: LimitSettings,
+    #[serde(default)]
+    pub access_log: AccessLogSettings,
+    #[serde(default)]
+    pub metrics: MetricsSettings,
+    #[serde(default)]
+    pub admin: AdminSettings,
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocolSettings,
+    #[serde(default)]
+    pub upstream: UpstreamSettings,
+    /// Access rules for path prefixes (`[[access]]` tables), checked along with `.geser-access` files.
+    #[serde(default)]
+    pub access: Vec<AccessRule>,
+    #[serde(default)]
+    pub registration: RegistrationSettings,
+    #[serde(default)]
+    pub paths: PathSettings,
+    #[serde(skip)]
+    pub source: ConfigSource,
+    /// Set once the server has chrooted or switched user, after which the configuration, the
+    /// certificate and the access log cannot be reliably opened by path again.
+    #[serde(skip)]
+    pub privileges_dropped: bool,
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+/// Key algorithm used for generated certificates.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+    /// ECDSA with the P-256 curve.
+    Ecdsa,
+    Ed25519,
+}
+
+/// Settings for generating a self-signed certificate on first start when
+/// cert_path and key_path do not exist (the `[self_signed]` table).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SelfSignedSettings {
+    pub enabled: bool,
+    pub key_type: KeyType,
+    pub validity_days: u32,
+}
+
+impl Default for SelfSignedSettings {
+    fn default() -> Self {
+        SelfSignedSettings {
+            enabled: true,
+            key_type: KeyType::Ecdsa,
+            validity_days: 3650,
+        }
+    }
+}
+
+/// Settings for accepting Titan uploads on the Gemini listener (the `[titan]` table).
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct TitanSettings {
+    pub enabled: bool,
+    pub max_size: usize,
+    pub tokens: Vec<String>,
+    pub allowed_fingerprints: Vec<String>,
+}
+
+impl Default for TitanSettings {
+    fn default() -> Self {
+        TitanSettings {
+            enabled: false,
+            max_size: 10 * 1024 * 1024,
+            tokens: Vec::new(),
+            allowed_fingerprints: Vec::new(),
+        }
+    }
+}
+
+// Tokens are secrets, so they are left out of the logged settings
+impl std::fmt::Debug for TitanSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TitanSettings")
+            .field("enabled", &self.enabled)
+            .field("max_size", &self.max_size)
+            .field("tokens", &format!("<{} redacted>", self.tokens.len()))
+            .field("allowed_fingerprints", &self.allowed_fingerprints)
+            .finish()
+    }
 }
 
 impl Settings {
-    // Creates a new Settings instance by loading configuration from a file and environment variables
-    pub fn new() -> Result<Self> {
-        let config = config::Config::builder()
-            .add_source(config::File::with_name("config").required(false))  // Optionally load config from "config" file
-            .add_source(config::Environment::with_prefix("GEMINI").separator("_")) // Load configuration from environment variables with "GEMINI" prefix
-            .build()?;
-        
-        config.try_deserialize::<Settings>().map_err(|e| e.into()) // Deserialize config into Settings struct
+    // Checks the settings for mistakes that would only show up once the server is running
+    pub fn validate(&self) -> Result<()> {
+        use std::net::ToSocketAddrs;
+        for address in std::iter::once(&self.address).chain(&self.listen) {
+            address.to_socket_addrs()
+                .map_err(|e| anyhow!("Invalid address {}: {}", address, e))?;
+        }
+        if !Path::new(&self.pages_dir).is_dir() {
+            return Err(anyhow!("pages_dir {} is not a directory", self.pages_dir));
+        }
+        if self.tls_reload_interval_secs == 0 {
+            return Err(anyhow!("tls_reload_interval_secs must be greater than zero"));
+        }
+        if self.titan.enabled && self.titan.tokens.is_empty() && self.titan.allowed_fingerprints.is_empty() {
+            return Err(anyhow!("titan is enabled but no tokens or allowed_fingerprints are configured"));
+        }
+        if self.rate_limit.enabled && (self.rate_limit.requests_per_minute == 0 || self.rate_limit.burst == 0) {
+            return Err(anyhow!("rate_limit requests_per_minute and burst must be greater than zero"));
+        }
+        if self.limits.max_connections == 0 || self.limits.max_connections_per_ip == 0 {
+            return Err(anyhow!("limits max_connections and max_connections_per_ip must be greater than zero"));
+        }
+        if self.admin.enabled && !self.admin.path.starts_with('/') {
+            return Err(anyhow!("admin path {} must start with /", self.admin.path));
+        }
+        if self.admin.enabled && self.admin.fingerprints.is_empty() {
+            return Err(anyhow!("admin is enabled but no fingerprints are configured"));
+        }
+        if self.proxy_protocol.enabled && self.proxy_protocol.trusted.is_empty() {
+            return Err(anyhow!("proxy_protocol is enabled but no trusted ranges are configured"));
+        }
+        if self.upstream.enabled && self.upstream.unix_socket.is_none() && self.upstream.trusted.is_empty() {
+            return Err(anyhow!("upstream is enabled but no trusted ranges are configured"));
+        }
+        if self.registration.enabled && !self.registration.path.starts_with('/') {
+            return Err(anyhow!("registration path {} must start with /", self.registration.path));
+        }
+        if let Some(invite) = self.registration.invites.iter().find(|invite| invite.code.is_empty() || !crate::registration::is_valid_username(&invite.username)) {
+            return Err(anyhow!("registration invite for {:?} needs a code and a username without spaces", invite.username));
+        }
+        if let Some(rule) = self.access.iter().find(|rule| !rule.prefix.starts_with('/')) {
+            return Err(anyhow!("access prefix {:?} must start with /", rule.prefix));
+        }
+        if self.key_passphrase_env.is_some() && self.key_passphrase_file.is_some() {
+            return Err(anyhow!("Only one of key_passphrase_env and key_passphrase_file may be set"));
+        }
+        Ok(())
+    }
+
+    // Reads the passphrase for an encrypted private key from the configured environment variable or file
+    pub fn key_passphrase(&self) -> Result<Option<String>> {
+        if let Some(name) = &self.key_passphrase_env {
+            let passphrase = std::env::var(name)
+                .map_err(|_| anyhow!("key_passphrase_env names {}, but it is not set", name))?;
+            return Ok(Some(passphrase));
+        }
+        if let Some(path) = &self.key_passphrase_file {
+            let passphrase = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Failed to read key_passphrase_file {}: {}", path, e))?;
+            return Ok(Some(passphrase.trim_end_matches(['\r', '\n']).to_string()));
+        }
+        Ok(None)
+    }
+}
+
+/// Flattens the pretty debug representation of the settings into `section.field: value` lines.
+fn flatten_debug(settings: &Settings) -> Vec<String> {
+    let debug = format!("{:#?}", settings);
+    let mut path: Vec<String> = Vec::new();
+    let mut lines = Vec::new();
+    for line in debug.lines().skip(1) {
+        let line = line.trim().trim_end_matches(',');
+        if line.starts_with(['}', ']', ')']) {
+            path.pop();
+            continue;
+        }
+        let (key, value) = match line.split_once(": ") {
+            Some((key, value)) => (Some(key), value),
+            None => (None, line),
+        };
+        let mut full_path = path.clone();
+        full_path.extend(key.map(str::to_string));
+        if value.ends_with(['{', '[', '(']) {
+            path.push(key.map(str::to_string).unwrap_or_default());
+        } else {
+            lines.push(format!("{}: {}", full_path.join("."), value));
+        }
+    }
+    lines
+}
+
+/// Lines of the debug representation that differ between two settings, as `- old` / `+ new`.
+/// Secrets are redacted by the Debug implementations, so the diff is safe to log.
+pub fn settings_diff(old: &Settings, new: &Settings) -> Vec<String> {
+    let old_lines = flatten_debug(old);
+    let new_lines = flatten_debug(new);
+    // Longest common subsequence table, so that lines only differing in position are not reported
+    let mut lcs = vec![vec![0usize; new_lines.len() + 1]; old_lines.len() + 1];
+    for i in (0..old_lines.len()).rev() {
+        for j in (0..new_lines.len()).rev() {
+            lcs[i][j] = if old_lines[i] == new_lines[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut diff = Vec::new();
+    while i < old_lines.len() || j < new_lines.len() {
+        if i < old_lines.len() && j < new_lines.len() && old_lines[i] == new_lines[j] {
+            i += 1;
+            j += 1;
+        } else if i < old_lines.len() && (j == new_lines.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push(format!("- {}", old_lines[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+ {}", new_lines[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+/// Settings that are only read at startup, so changing them needs a restart.
+fn restart_required(old: &Settings, new: &Settings) -> Vec<&'static str> {
+    let checks = [
+        ("address", old.address != new.address),
+        ("listen", old.listen != new.listen),
+        ("ipv6_only", old.ipv6_only != new.ipv6_only),
+        ("unix_socket", old.unix_socket != new.unix_socket),
+        ("user", old.user != new.user),
+        ("group", old.group != new.group),
+        ("chroot", old.chroot != new.chroot),
+        ("cert_path", old.cert_path != new.cert_path),
+        ("key_path", old.key_path != new.key_path),
+        ("key_passphrase_env", old.key_passphrase_env != new.key_passphrase_env),
+        ("key_passphrase_file", old.key_passphrase_file != new.key_passphrase_file),
+        ("tls_reload_interval_secs", old.tls_reload_interval_secs != new.tls_reload_interval_secs),
+        ("spartan.enabled", old.spartan.enabled != new.spartan.enabled),
+        ("spartan.address", old.spartan.address != new.spartan.address),
+        ("gopher.enabled", old.gopher.enabled != new.gopher.enabled),
+        ("gopher.address", old.gopher.address != new.gopher.address),
+        ("http.enabled", old.http.enabled != new.http.enabled),
+        ("http.address", old.http.address != new.http.address),
+        ("upstream.enabled", old.upstream.enabled != new.upstream.enabled),
+        ("upstream.address", old.upstream.address != new.upstream.address),
+        ("upstream.unix_socket", old.upstream.unix_socket != new.upstream.unix_socket),
+        ("metrics.enabled", old.metrics.enabled != new.metrics.enabled),
+        ("registration.enabled", old.registration.enabled != new.registration.enabled),
+        ("registration.store", old.registration.store != new.registration.store),
+        ("metrics.address", old.metrics.address != new.metrics.address),
+        ("limits.max_connections", old.limits.max_connections != new.limits.max_connections),
+        ("access_log", format!("{:?}", old.access_log) != format!("{:?}", new.access_log)),
+    ];
+    checks.iter().filter(|(_, changed)| *changed).map(|(name, _)| *name).collect()
+}
+
+/// Loads the configuration again and swaps it in if it is valid. An invalid configuration is
+/// rejected with the reason and a diff against the current one, and the current one is kept.
+/// Returns whether the new settings were swapped in.
+pub fn reload_settings(sender: &watch::Sender<Arc<Settings>>) -> bool {
+    let current = sender.borrow().clone();
+    if current.privileges_dropped {
+        // After a chroot relative paths and pages_dir no longer resolve as configured, and the
+        // unprivileged user may not be able to read what root could
+        metrics::increment(&METRICS.config_reload_failures);
+        tracing::warn!("Configuration reloads are disabled after dropping privileges; restart the server to apply changes");
+        return false;
+    }
+    let new = match current.source.load() {
+        Ok(new) => new,
+        Err(e) => {
+            metrics::increment(&METRICS.config_reload_failures);
+            tracing::error!("Failed to load configuration, keeping the current one: {:?}", e);
+            return false;
+        }
+    };
+    let diff = settings_diff(&current, &new);
+    if let Err(e) = new.validate() {
+        metrics::increment(&METRICS.config_reload_failures);
+        tracing::error!("Rejected invalid configuration, keeping the current one: {}\n{}", e, diff.join("\n"));
+        return false;
+    }
+    if diff.is_empty() {
+        tracing::info!("Configuration reloaded, nothing changed");
+    } else {
+        tracing::info!("Configuration reloaded:\n{}", diff.join("\n"));
+    }
+    let restart = restart_required(&current, &new);
+    if !restart.is_empty() {
+        tracing::warn!("Changes to {} only take effect after a restart", restart.join(", "));
+    }
+    sender.send_replace(Arc::new(new));
+    metrics::increment(&METRICS.config_reloads);
+    true
+}
+
+/// Background task that reloads the configuration when its file changes and on SIGHUP.
+pub async fn config_reload_task(sender: watch::Sender<Arc<Settings>>) {
+    let config_file = sender.borrow().source.watched_file();
+    let (changes_tx, mut changes) = mpsc::unbounded_channel();
+    // The watcher stops when dropped, so it is kept alive for the lifetime of the task
+    let _watcher = watch_files(&[&config_file], changes_tx)
+        .map_err(|e| tracing::warn!("Cannot watch {} for changes, reload with SIGHUP instead: {}", config_file, e))
+        .ok();
+    let mut hangup = Hangup::new();
+    loop {
+        tokio::select! {
+            Some(()) = changes.recv() => {
+                // Wait for editors to finish writing before reading the file
+                tokio::time::sleep(CONFIG_RELOAD_DEBOUNCE).await;
+                while changes.try_recv().is_ok() {}
+                tracing::info!("{} changed, reloading configuration", config_file);
+            },
+            _ = hangup.recv() => tracing::info!("Received SIGHUP, reloading configuration"),
+        }
+        reload_settings(&sender);
+    }
+}
+
+/// How long to wait after a change to the configuration file before reloading it.
+const CONFIG_RELOAD_DEBOUNCE: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Settings for the optional plaintext Spartan listener (the `[spartan]` table).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SpartanSettings {
+    pub enabled: bool,
+    pub address: String,
+    pub max_upload_size: usize,
+}
+
+impl Default for SpartanSettings {
+    fn default() -> Self {
+        SpartanSettings {
+            enabled: false,
+            address: "0.0.0.0:300".to_string(),
+            max_upload_size: 1024 * 1024,
+        }
+    }
+}
+
+/// Settings for the optional Gopher gateway (the `[gopher]` table).
+/// `hostname` and `port` are the public values written into menu items.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GopherSettings {
+    pub enabled: bool,
+    pub address: String,
+    pub hostname: String,
+    pub port: u16,
+}
+
+impl Default for GopherSettings {
+    fn default() -> Self {
+        GopherSettings {
+            enabled: false,
+            address: "0.0.0.0:70".to_string(),
+            hostname: "localhost".to_string(),
+            port: 70,
+        }
+    }
+}
+
+/// Settings for the optional plain-HTTP preview listener (the `[http]` table).
+/// It binds to localhost by default, since it is meant for authors rather than readers.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HttpSettings {
+    pub enabled: bool,
+    pub address: String,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            enabled: false,
+            address: "127.0.0.1:8080".to_string(),
+        }
+    }
+}
+
+/// Per-client request rate limiting on the Gemini listener (the `[rate_limit]` table).
+/// Each IPv4 address or IPv6 /64 gets a token bucket holding `burst` requests, refilled at
+/// `requests_per_minute`. Clients that send more than `ban_after` requests while being told
+/// to slow down are refused for `ban_secs`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    pub enabled: bool,
+    pub requests_per_minute: u32,
+    pub burst: u32,
+    pub ban_after: u32,
+    pub ban_secs: u64,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        RateLimitSettings {
+            enabled: false,
+            requests_per_minute: 60,
+            burst: 20,
+            ban_after: 10,
+            ban_secs: 600,
+        }
+    }
+}
+
+/// Connection limits and timeouts for the Gemini listener (the `[limits]` table).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LimitSettings {
+    /// Time allowed for the TLS handshake.
+    pub handshake_timeout_secs: u64,
+    /// Time allowed for the client to send the request line after the handshake.
+    pub request_timeout_secs: u64,
+    /// Time allowed for sending the response, including receiving a Titan upload.
+    pub write_timeout_secs: u64,
+    /// Connections handled at once; further clients wait in the listen backlog.
+    pub max_connections: usize,
+    /// Connections one client (IPv4 address or IPv6 /64) may hold open at once.
+    pub max_connections_per_ip: usize,
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        LimitSettings {
+            handshake_timeout_secs: 10,
+            request_timeout_secs: 10,
+            write_timeout_secs: 60,
+            max_connections: 1024,
+            max_connections_per_ip: 16,
+        }
+    }
+}
+
+/// Settings for the optional Prometheus metrics listener (the `[metrics]` table).
+/// It binds to localhost by default; the metrics are served at `/metrics`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetricsSettings {
+    pub enabled: bool,
+    pub address: String,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        MetricsSettings {
+            enabled: false,
+            address: "127.0.0.1:9090".to_string(),
+        }
+    }
+}
+
+/// Settings for the admin status page on the Gemini listener (the `[admin]` table).
+/// Only clients presenting a certificate with one of the `fingerprints` may use it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AdminSettings {
+    pub enabled: bool,
+    pub path: String,
+    pub fingerprints: Vec<String>,
+}
+
+impl Default for AdminSettings {
+    fn default() -> Self {
+        AdminSettings {
+            enabled: false,
+            path: "/.well-known/geser/status".to_string(),
+            fingerprints: Vec::new(),
+        }
+    }
+}
+
+/// Settings for reading PROXY protocol v1/v2 headers on the Gemini listener (the `[proxy_protocol]` table).
+/// Connections from the `trusted` ranges must start with a header, whose client address is then used
+/// in place of the balancer's; connections from anywhere else are served directly.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ProxyProtocolSettings {
+    pub enabled: bool,
+    pub trusted: Vec<IpNet>,
+}
+
+impl Default for ProxyProtocolSettings {
+    fn default() -> Self {
+        ProxyProtocolSettings {
+            enabled: false,
+            trusted: vec![
+                IpNet::from(std::net::IpAddr::from([127, 0, 0, 1])),
+                IpNet::from(std::net::IpAddr::from(std::net::Ipv6Addr::LOCALHOST)),
+            ],
+        }
+    }
+}
+
+/// How a TLS-terminating upstream passes the client's certificate hash and SNI.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamPass {
+    /// In TLVs of a PROXY protocol v2 header, which also carries the client's address.
+    Proxy,
+    /// In `Client-Cert:` and `SNI:` lines ahead of the request, ended by an empty line.
+    Header,
+}
+
+/// Settings for the plaintext upstream listener (the `[upstream]` table), for deployments that
+/// terminate TLS in a separate proxy. Only connections from the `trusted` ranges are accepted;
+/// when `unix_socket` is set it is listened on instead of `address`, guarded by its file permissions.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UpstreamSettings {
+    pub enabled: bool,
+    pub address: String,
+    pub unix_socket: Option<String>,
+    pub trusted: Vec<IpNet>,
+    pub pass: UpstreamPass,
+    /// PROXY v2 TLV type carrying the client certificate's SHA-256 hash.
+    pub cert_tlv: u8,
+}
+
+impl Default for UpstreamSettings {
+    fn default() -> Self {
+        UpstreamSettings {
+            enabled: false,
+            address: "127.0.0.1:1966".to_string(),
+            unix_socket: None,
+            trusted: ProxyProtocolSettings::default().trusted,
+            pass: UpstreamPass::Proxy,
+            cert_tlv: 0xe0,
+        }
+    }
+}
+
+/// Restrictions for a path prefix, from an `[[access]]` table or a `.geser-access` file.
+/// In access files the prefix is the file's directory. Requests are refused if their address
+/// is in `deny` or, when `allow` is set, not in `allow`; `require_cert` asks for a client
+/// certificate, and `fingerprints` and `users` (registered usernames) limit which ones are accepted.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessRule {
+    pub prefix: String,
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+    pub require_cert: bool,
+    pub fingerprints: Vec<String>,
+    pub users: Vec<String>,
+}
+
+/// An invite code and the username a certificate registered with it gets.
+#[derive(Clone, Deserialize)]
+pub struct Invite {
+    pub code: String,
+    pub username: String,
+}
+
+/// Settings for registering client certificates (the `[registration]` table): a client answers
+/// the prompt at `path` with an invite code, and its certificate is stored in `store` under the
+/// invite's username. Each invite registers one certificate.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct RegistrationSettings {
+    pub enabled: bool,
+    pub path: String,
+    pub store: String,
+    pub invites: Vec<Invite>,
+}
+
+impl Default for RegistrationSettings {
+    fn default() -> Self {
+        RegistrationSettings {
+            enabled: false,
+            path: "/register".to_string(),
+            store: "registrations.txt".to_string(),
+            invites: Vec::new(),
+        }
+    }
+}
+
+// Invite codes are secrets, so only the usernames are logged
+impl std::fmt::Debug for RegistrationSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegistrationSettings")
+            .field("enabled", &self.enabled)
+            .field("path", &self.path)
+            .field("store", &self.store)
+            .field("invites", &self.invites.iter().map(|invite| invite.username.as_str()).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// How request paths are mapped onto files in pages_dir (the `[paths]` table).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PathSettings {
+    pub symlinks: SymlinkPolicy,
+    /// Serve files and directories whose names start with a dot, such as `.git`.
+    pub allow_hidden: bool,
+}
+
+/// Line format of the access log.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Plain,
+    Json,
+}
+
+/// When the access log file is rotated.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    Never,
+    /// When the file would grow beyond `max_size` bytes.
+    Size,
+    /// At the first request of each day (UTC).
+    Daily,
+}
+
+/// Settings for the access log of the Gemini listener (the `[access_log]` table).
+/// Without a `path`, entries are written to stdout.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AccessLogSettings {
+    pub enabled: bool,
+    pub path: Option<String>,
+    pub format: AccessLogFormat,
+    /// Log only the /24 of IPv4 and the /48 of IPv6 client addresses.
+    pub anonymize_ip: bool,
+    pub rotation: Rotation,
+    pub max_size: u64,
+    /// Number of rotated files to keep.
+    pub keep: usize,
+}
+
+impl Default for AccessLogSettings {
+    fn default() -> Self {
+        AccessLogSettings {
+            enabled: false,
+            path: None,
+            format: AccessLogFormat::Plain,
+            anonymize_ip: false,
+            rotation: Rotation::Never,
+            max_size: 10 * 1024 * 1024,
+            keep: 7,
+        }
     }
 }
 
@@ -39,7 +761,7 @@ mod tests {
         env::set_var("GEMINI_TLS_RELOAD_INTERVAL_SECS", "300");
 
         // Load settings
-        let settings = Settings::new().unwrap();
+        let settings = ConfigSource::default().load().unwrap();
 
         // Check if the environment variables were correctly loaded
         assert_eq!(settings.address, "0.0.0.0:1965");
@@ -47,6 +769,127 @@ mod tests {
         assert_eq!(settings.key_path, "key.pem");
         assert_eq!(settings.pages_dir, "pages");
         assert_eq!(settings.tls_reload_interval_secs, 300);
+        assert_eq!(settings.shutdown_timeout_secs, 30);
+        assert!(settings.self_signed.enabled, "Certificates should be generated by default");
+        assert_eq!(settings.self_signed.key_type, KeyType::Ecdsa);
+        assert!(!settings.titan.enabled, "Titan uploads should be disabled by default");
+        assert!(!settings.spartan.enabled, "The Spartan listener should be disabled by default");
+        assert_eq!(settings.spartan.address, "0.0.0.0:300");
+        assert!(!settings.gopher.enabled, "The Gopher gateway should be disabled by default");
+        assert_eq!(settings.gopher.port, 70);
+        assert!(!settings.http.enabled, "The HTTP preview should be disabled by default");
+        assert_eq!(settings.http.address, "127.0.0.1:8080");
+        assert!(!settings.rate_limit.enabled, "Rate limiting should be disabled by default");
+        assert_eq!(settings.limits.max_connections, 1024);
+        assert!(!settings.metrics.enabled, "The metrics listener should be disabled by default");
+        assert_eq!(settings.admin.path, "/.well-known/geser/status");
+        assert!(!settings.proxy_protocol.enabled);
+        assert_eq!(settings.proxy_protocol.trusted.len(), 2, "Only localhost is trusted by default");
+    }
+
+    // Test loading settings from an explicit config file path
+    #[test]
+    fn test_settings_load_path() {
+        let source = |path: &str| ConfigSource { path: Some(path.to_string()), overrides: Vec::new() };
+        assert!(source("config.toml").load().is_ok());
+        assert!(source("does-not-exist.toml").load().is_err(), "An explicit config file must exist");
+
+        let overridden = ConfigSource { overrides: vec![("pages_dir".to_string(), "capsule".to_string())], ..source("config.toml") };
+        let settings = overridden.load().unwrap();
+        assert_eq!(settings.pages_dir, "capsule", "Overrides take precedence over the file");
+        assert_eq!(settings.source.overrides.len(), 1, "The source is kept for reloading");
+    }
+
+    // Test validation of settings
+    #[test]
+    fn test_settings_validate() {
+        let settings = Settings {
+            address: "127.0.0.1:1965".to_string(),
+            pages_dir: "pages".to_string(),
+            tls_reload_interval_secs: 300,
+            ..Settings::default()
+        };
+        assert!(settings.validate().is_ok());
+        assert!(Settings { address: "nonsense".to_string(), ..Settings::default() }.validate().is_err());
+
+        let mut settings = settings;
+        settings.listen = vec!["[::1]:1965".to_string(), "nonsense".to_string()];
+        assert!(settings.validate().is_err(), "Every listen address must be valid");
+        settings.listen.clear();
+        settings.titan.enabled = true;
+        assert!(settings.validate().is_err(), "Titan needs tokens or fingerprints");
+        settings.titan.enabled = false;
+        settings.limits.max_connections = 0;
+        assert!(settings.validate().is_err(), "A server without connection slots would accept nothing");
+    }
+
+    // Test reading the key passphrase from an environment variable or a file
+    #[test]
+    fn test_key_passphrase() {
+        assert_eq!(Settings::default().key_passphrase().unwrap(), None);
+
+        env::set_var("GESER_TEST_KEY_PASSPHRASE", "hunter2");
+        let settings = Settings { key_passphrase_env: Some("GESER_TEST_KEY_PASSPHRASE".to_string()), ..Settings::default() };
+        assert_eq!(settings.key_passphrase().unwrap().as_deref(), Some("hunter2"));
+        let settings = Settings { key_passphrase_env: Some("GESER_TEST_UNSET_PASSPHRASE".to_string()), ..Settings::default() };
+        assert!(settings.key_passphrase().is_err(), "A missing environment variable is an error");
+
+        let path = env::temp_dir().join(format!("geser-passphrase-{}", std::process::id()));
+        std::fs::write(&path, "correct horse\n").unwrap();
+        let settings = Settings { key_passphrase_file: Some(path.to_string_lossy().to_string()), ..Settings::default() };
+        assert_eq!(settings.key_passphrase().unwrap().as_deref(), Some("correct horse"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Test that a reloaded configuration is swapped in only if it is valid
+    #[test]
+    fn test_reload_settings() {
+        let path = env::temp_dir().join(format!("geser-reload-{}.toml", std::process::id()));
+        let base = "address = \"127.0.0.1:1965\"\ncert_path = \"cert.pem\"\nkey_path = \"key.pem\"\npages_dir = \"pages\"\ntls_reload_interval_secs = 300\n";
+        std::fs::write(&path, base).unwrap();
+        let source = ConfigSource { path: Some(path.to_string_lossy().to_string()), overrides: Vec::new() };
+        let (sender, receiver) = watch::channel(Arc::new(source.load().unwrap()));
+
+        std::fs::write(&path, format!("{}hostnames = [\"example.org\"]\n", base)).unwrap();
+        assert!(reload_settings(&sender));
+        assert_eq!(receiver.borrow().hostnames, vec!["example.org".to_string()]);
+
+        std::fs::write(&path, format!("{}[titan]\nenabled = true\n", base)).unwrap();
+        assert!(!reload_settings(&sender), "Titan without credentials is invalid");
+        assert!(!receiver.borrow().titan.enabled, "The current settings should be kept");
+        std::fs::write(&path, "address = [").unwrap();
+        assert!(!reload_settings(&sender), "Unparseable configuration is rejected");
+        assert_eq!(receiver.borrow().hostnames, vec!["example.org".to_string()]);
+        std::fs::remove_file(&path).unwrap();
+
+        let old = Settings::default();
+        let new = Settings { hostnames: vec!["example.org".to_string()], ..Settings::default() };
+        let diff = settings_diff(&old, &new);
+        assert_eq!(diff, vec!["- hostnames: []".to_string(), "+ hostnames: \"example.org\"".to_string()]);
+        let titan = Settings { titan: TitanSettings { enabled: true, ..TitanSettings::default() }, ..Settings::default() };
+        assert_eq!(settings_diff(&old, &titan), vec!["- titan.enabled: false".to_string(), "+ titan.enabled: true".to_string()]);
+        assert_eq!(restart_required(&old, &Settings { address: "[::]:1965".to_string(), ..Settings::default() }), vec!["address"]);
+    }
+
+    // Test that reloads are refused once privileges are dropped, since after a chroot the file,
+    // relative paths and pages_dir no longer resolve as configured
+    #[test]
+    fn test_reload_after_dropping_privileges() {
+        let path = env::temp_dir().join(format!("geser-reload-dropped-{}.toml", std::process::id()));
+        let base = "address = \"127.0.0.1:1965\"\ncert_path = \"cert.pem\"\nkey_path = \"key.pem\"\npages_dir = \"pages\"\ntls_reload_interval_secs = 300\nchroot = true\n";
+        std::fs::write(&path, base).unwrap();
+        let source = ConfigSource { path: Some(path.to_string_lossy().to_string()), overrides: Vec::new() };
+        let mut settings = source.load().unwrap();
+        // As left by drop_privileges after a chroot into pages_dir
+        settings.pages_dir = "/".to_string();
+        settings.privileges_dropped = true;
+        let (sender, receiver) = watch::channel(Arc::new(settings));
+
+        std::fs::write(&path, format!("{}hostnames = [\"example.org\"]\n", base)).unwrap();
+        assert!(!reload_settings(&sender), "Reloads are refused after dropping privileges");
+        assert_eq!(receiver.borrow().pages_dir, "/", "The chrooted pages_dir should be kept");
+        assert!(receiver.borrow().hostnames.is_empty());
+        std::fs::remove_file(&path).unwrap();
     }
 
     // Test loading settings from file (if the file exists)
@@ -57,7 +900,7 @@ mod tests {
         // This test is for illustration purposes only and may not pass unless you have a file.
 
         // Normally you would load a config file like this:
-        // let settings = Settings::new().unwrap();
+        // let settings = ConfigSource::default().load().unwrap();
 
         // Check if settings were loaded correctly from the config file
         // You can test individual values here
@@ -0,0 +1,416 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use anyhow::{Result, Context, anyhow};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use url::Url;
+
+use crate::access;
+use crate::config::Settings;
+use crate::html;
+use crate::pages;
+use crate::resolve;
+
+/// Where `geser build` writes its output.
+pub struct ExportOptions {
+    pub output_dir: PathBuf,
+    /// When set, an HTML version of the site is written to this directory as well.
+    pub html_dir: Option<PathBuf>,
+}
+
+/// Summary of a static export: what was written and anything the author should fix.
+#[derive(Debug, Default)]
+pub struct ExportReport {
+    pub pages: usize,
+    pub assets: usize,
+    pub indexes: usize,
+    pub warnings: Vec<String>,
+}
+
+/// A page found in pages_dir, with the URL path Geser serves it under.
+struct SourcePage {
+    file: String,
+    url_path: String,
+    title: String,
+    modified: SystemTime,
+    gemtext: String,
+}
+
+/// Exports the whole capsule: every Markdown page is converted to gemtext (and optionally HTML),
+/// static files are copied, directories without an index get one, and an Atom feed is generated.
+pub fn export_site(settings: &Settings, options: &ExportOptions) -> Result<ExportReport> {
+    let root = Path::new(&settings.pages_dir);
+    let mut report = ExportReport::default();
+    let mut files = Vec::new();
+    let mut dirs = BTreeSet::new();
+    dirs.insert("/".to_string());
+    let mut ancestors = HashSet::new();
+    ancestors.insert(root.canonicalize().with_context(|| format!("Failed to read directory {}", root.display()))?);
+    collect_files(settings, "/", &mut files, &mut dirs, &mut report.warnings, &mut ancestors)?;
+    let file_set: BTreeSet<String> = files.iter().cloned().collect();
+
+    let mut source_pages = Vec::new();
+
+    for file in &files {
+        let source = root.join(file.trim_start_matches('/'));
+        if file.ends_with(".md") {
+            let content = fs::read_to_string(&source)
+                .with_context(|| format!("Failed to read page {}", source.display()))?;
+            let (gemtext, warnings) = pages::markdown_to_gemtext_with_warnings(&content);
+            report.warnings.extend(warnings.into_iter().map(|w| format!("{}: {}", file, w)));
+            let url_path = page_url_path(file);
+            source_pages.push(SourcePage {
+                file: file.clone(),
+                title: page_title(&gemtext).unwrap_or_else(|| file_stem(file)),
+                modified: fs::metadata(&source).and_then(|m| m.modified()).unwrap_or(SystemTime::UNIX_EPOCH),
+                gemtext: pages::fill_input(&pages::fill_username(&pages::rewrite_prompt_links(&gemtext), None), None),
+                url_path,
+            });
+        } else {
+            copy_file(&source, &options.output_dir.join(file.trim_start_matches('/')))?;
+            if let Some(html_dir) = &options.html_dir {
+                copy_file(&source, &html_dir.join(file.trim_start_matches('/')))?;
+            }
+            report.assets += 1;
+        }
+    }
+
+    for page in &source_pages {
+        let (gemtext, broken) = rewrite_internal_links(&page.gemtext, &page.url_path, &file_set, &dirs, "gmi");
+        report.warnings.extend(broken.into_iter().map(|link| format!("{}: broken internal link {}", page.file, link)));
+        let output_stem = page.file.trim_start_matches('/').strip_suffix(".md").unwrap_or(&page.file);
+        let output_name = format!("{}.gmi", output_stem);
+        write_file(&options.output_dir.join(&output_name), gemtext.as_bytes())?;
+
+        if let Some(html_dir) = &options.html_dir {
+            let (for_html, _) = rewrite_internal_links(&page.gemtext, &page.url_path, &file_set, &dirs, "html");
+            let document = html::html_document(&for_html, &settings.hostnames);
+            write_file(&html_dir.join(format!("{}.html", output_stem)), document.as_bytes())?;
+        }
+        report.pages += 1;
+    }
+
+    // Directories without an index page get a generated listing.
+    for dir in &dirs {
+        if file_set.contains(&format!("{}index.md", dir)) {
+            continue;
+        }
+        let index = directory_index(dir, &files, &dirs, &source_pages, "gmi");
+        write_file(&options.output_dir.join(dir.trim_start_matches('/')).join("index.gmi"), index.as_bytes())?;
+        if let Some(html_dir) = &options.html_dir {
+            let index = directory_index(dir, &files, &dirs, &source_pages, "html");
+            let document = html::html_document(&index, &settings.hostnames);
+            write_file(&html_dir.join(dir.trim_start_matches('/')).join("index.html"), document.as_bytes())?;
+        }
+        report.indexes += 1;
+    }
+
+    let host = match settings.hostnames.first() {
+        Some(host) => host.as_str(),
+        None => {
+            report.warnings.push("no hostnames configured; feed links use localhost".to_string());
+            "localhost"
+        }
+    };
+    write_file(&options.output_dir.join("atom.xml"), atom_feed(host, &source_pages)?.as_bytes())?;
+
+    Ok(report)
+}
+
+/// Recursively collects the files under a directory as URL-style paths (e.g. `/blog/post.md`),
+/// skipping hidden files and directories. Entries are resolved under the configured symlink
+/// policy and anything the server would refuse to serve, or only serves to some clients because
+/// of `[[access]]` rules or `.geser-access` files, is left out with a warning.
+fn collect_files(
+    settings: &Settings,
+    url_dir: &str,
+    files: &mut Vec<String>,
+    dirs: &mut BTreeSet<String>,
+    warnings: &mut Vec<String>,
+    ancestors: &mut HashSet<PathBuf>,
+) -> Result<()> {
+    let root = Path::new(&settings.pages_dir);
+    let dir = root.join(url_dir.trim_start_matches('/'));
+    let mut entries = fs::read_dir(&dir)
+        .with_context(|| format!("Failed to read directory {}", dir.display()))?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        let url_path = format!("{}{}", url_dir, name);
+        let resolved = match resolve::resolve_file(root, &url_path, settings.paths.symlinks) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                warnings.push(format!("{}: not exported, {}", url_path, e));
+                continue;
+            }
+        };
+        let is_dir = fs::metadata(&resolved)
+            .with_context(|| format!("Failed to read {}", resolved.display()))?
+            .is_dir();
+        let served_as = if is_dir { format!("{}/", url_path) } else { page_url_path(&url_path) };
+        if restricted(settings, &served_as)? {
+            warnings.push(format!("{}: not exported, access is restricted", served_as));
+            continue;
+        }
+        if is_dir {
+            // A symlink back to a directory above it would recurse forever
+            if !ancestors.insert(resolved.clone()) {
+                warnings.push(format!("{}: not exported, symlink loop", served_as));
+                continue;
+            }
+            dirs.insert(served_as.clone());
+            collect_files(settings, &served_as, files, dirs, warnings, ancestors)?;
+            ancestors.remove(&resolved);
+        } else {
+            files.push(url_path);
+        }
+    }
+    Ok(())
+}
+
+// The URL path a file in pages_dir is served under: Markdown pages lose their extension and
+// index pages stand for their directory.
+fn page_url_path(file: &str) -> String {
+    match file.strip_suffix(".md") {
+        Some(stem) => match stem.strip_suffix("/index") {
+            Some(dir) => format!("{}/", dir),
+            None => stem.to_string(),
+        },
+        None => file.to_string(),
+    }
+}
+
+// Checks whether any access rule for a path refuses some clients. An unreadable access file
+// fails the export rather than publishing what it protects.
+fn restricted(settings: &Settings, url_path: &str) -> Result<bool> {
+    let rules = access::applicable_rules(&settings.access, &settings.pages_dir, url_path, settings.paths.symlinks)?;
+    Ok(rules.iter().any(access::restricts))
+}
+
+/// Rewrites internal links to point at the exported files: `/about` becomes `/about.gmi` (or `.html`).
+/// Returns the rewritten gemtext and the internal links that do not resolve to anything.
+fn rewrite_internal_links(
+    gemtext: &str,
+    url_path: &str,
+    files: &BTreeSet<String>,
+    dirs: &BTreeSet<String>,
+    extension: &str,
+) -> (String, Vec<String>) {
+    let mut output = String::new();
+    let mut broken = Vec::new();
+    let base = Url::parse(&format!("gemini://localhost{}", url_path)).ok();
+
+    for line in gemtext.split_inclusive('\n') {
+        let rewritten = line.strip_prefix("=>").and_then(|rest| {
+            let rest = rest.trim_start();
+            let target = rest.split_whitespace().next()?;
+            if !matches!(Url::parse(target), Err(url::ParseError::RelativeUrlWithoutBase)) {
+                return None;
+            }
+            let resolved = base.as_ref()?.join(target).ok()?;
+            let path = percent_encoding::percent_decode_str(resolved.path()).decode_utf8_lossy().to_string();
+            if files.contains(&path) || dirs.contains(&path) || dirs.contains(&format!("{}/", path)) {
+                // Static files and directories keep their links; directories get an index in the export.
+                None
+            } else if files.contains(&format!("{}.md", path)) {
+                let split = target.find(['?', '#']).unwrap_or(target.len());
+                let new_target = format!("{}.{}{}", &target[..split], extension, &target[split..]);
+                Some(line.replacen(target, &new_target, 1))
+            } else {
+                broken.push(target.to_string());
+                None
+            }
+        });
+        output.push_str(rewritten.as_deref().unwrap_or(line));
+    }
+    (output, broken)
+}
+
+/// Generates a gemtext listing of a directory's subdirectories, pages and files.
+fn directory_index(
+    dir: &str,
+    files: &[String],
+    dirs: &BTreeSet<String>,
+    source_pages: &[SourcePage],
+    extension: &str,
+) -> String {
+    let mut output = format!("# Index of {}\n\n", dir);
+    for sub in dirs.iter().filter(|d| d.as_str() != dir && parent_dir(d.trim_end_matches('/')) == dir) {
+        let name = sub.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+        output.push_str(&format!("=> {}/ {}/\n", name, name));
+    }
+    for file in files.iter().filter(|f| parent_dir(f) == dir) {
+        let name = file.rsplit('/').next().unwrap_or("");
+        match source_pages.iter().find(|page| &page.file == file) {
+            Some(page) => {
+                let link = format!("{}.{}", name.strip_suffix(".md").unwrap_or(name), extension);
+                output.push_str(&format!("=> {} {}\n", link, page.title));
+            },
+            None => output.push_str(&format!("=> {} {}\n", name, name)),
+        }
+    }
+    output
+}
+
+/// Builds an Atom feed of all pages, newest first.
+fn atom_feed(host: &str, source_pages: &[SourcePage]) -> Result<String> {
+    let mut entries: Vec<&SourcePage> = source_pages.iter().collect();
+    entries.sort_by_key(|page| std::cmp::Reverse(page.modified));
+    let updated = entries.first().map(|page| page.modified).unwrap_or(SystemTime::UNIX_EPOCH);
+
+    let mut feed = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    feed.push_str(&format!("  <title>{}</title>\n", html::escape(host)));
+    feed.push_str(&format!("  <id>gemini://{}/</id>\n", html::escape(host)));
+    feed.push_str(&format!("  <link href=\"gemini://{}/\"/>\n", html::escape(host)));
+    feed.push_str(&format!("  <updated>{}</updated>\n", format_time(updated)?));
+    for page in entries {
+        let url = html::escape(&format!("gemini://{}{}", host, page.url_path));
+        feed.push_str("  <entry>\n");
+        feed.push_str(&format!("    <title>{}</title>\n", html::escape(&page.title)));
+        feed.push_str(&format!("    <id>{}</id>\n", url));
+        feed.push_str(&format!("    <link href=\"{}\"/>\n", url));
+        feed.push_str(&format!("    <updated>{}</updated>\n", format_time(page.modified)?));
+        feed.push_str("  </entry>\n");
+    }
+    feed.push_str("</feed>\n");
+    Ok(feed)
+}
+
+fn format_time(time: SystemTime) -> Result<String> {
+    OffsetDateTime::from(time).format(&Rfc3339).map_err(|e| anyhow!("Failed to format time: {:?}", e))
+}
+
+/// Returns the text of the first heading in the gemtext, if any.
+fn page_title(gemtext: &str) -> Option<String> {
+    gemtext.lines()
+        .find_map(|line| line.strip_prefix('#'))
+        .map(|heading| heading.trim_start_matches('#').trim().to_string())
+        .filter(|title| !title.is_empty())
+}
+
+fn file_stem(file: &str) -> String {
+    Path::new(file).file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default()
+}
+
+/// Returns the directory part of a URL-style path, with a trailing slash.
+fn parent_dir(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(idx) => &path[..=idx],
+        None => "/",
+    }
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+    fs::write(path, data).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+    fs::copy(from, to).with_context(|| format!("Failed to copy {} to {}", from.display(), to.display()))?;
+    Ok(())
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+
+    // Test link rewriting and broken link detection
+    #[test]
+    fn test_rewrite_internal_links() {
+        let files: BTreeSet<String> = ["/about.md", "/blog/index.md", "/blog/post.md", "/images/a.png"]
+            .iter().map(|s| s.to_string()).collect();
+        let dirs: BTreeSet<String> = ["/", "/blog/", "/images/"].iter().map(|s| s.to_string()).collect();
+        let gemtext = "=> /about About\n=> post#top Post\n=> /images/a.png\n=> /blog/ Blog\n=> /missing Gone\n=> gemini://other.org/x Other\ntext\n";
+        let (output, broken) = rewrite_internal_links(gemtext, "/blog/index", &files, &dirs, "gmi");
+        assert_eq!(output, "=> /about.gmi About\n=> post.gmi#top Post\n=> /images/a.png\n=> /blog/ Blog\n=> /missing Gone\n=> gemini://other.org/x Other\ntext\n");
+        assert_eq!(broken, vec!["/missing".to_string()]);
+    }
+
+    // Test a full export into a temporary directory
+    #[test]
+    fn test_export_site() {
+        let base = std::env::temp_dir().join(format!("geser-export-{}", std::process::id()));
+        let pages_dir = base.join("pages");
+        fs::create_dir_all(pages_dir.join("blog")).unwrap();
+        fs::write(pages_dir.join("index.md"), "# Home\n\n[Blog](/blog/first)\n\n[Nowhere](/nowhere)").unwrap();
+        fs::write(pages_dir.join("blog/first.md"), "# First post\n\n<b>bold</b>").unwrap();
+        fs::write(pages_dir.join("logo.png"), [1, 2, 3]).unwrap();
+
+        let settings = Settings {
+            pages_dir: pages_dir.to_string_lossy().to_string(),
+            hostnames: vec!["example.org".to_string()],
+            ..Settings::default()
+        };
+        let options = ExportOptions {
+            output_dir: base.join("public"),
+            html_dir: Some(base.join("html")),
+        };
+        let report = export_site(&settings, &options).unwrap();
+
+        assert_eq!((report.pages, report.assets, report.indexes), (2, 1, 1));
+        assert!(report.warnings.iter().any(|w| w.contains("broken internal link /nowhere")));
+        assert!(report.warnings.iter().any(|w| w.contains("raw HTML is dropped")));
+        let index = fs::read_to_string(base.join("public/index.gmi")).unwrap();
+        assert!(index.contains("=> /blog/first.gmi Blog"));
+        let blog_index = fs::read_to_string(base.join("public/blog/index.gmi")).unwrap();
+        assert!(blog_index.contains("=> first.gmi First post"));
+        assert!(base.join("public/logo.png").exists());
+        assert!(fs::read_to_string(base.join("public/atom.xml")).unwrap().contains("gemini://example.org/blog/first"));
+        assert!(fs::read_to_string(base.join("html/index.html")).unwrap().contains("<a href=\"/blog/first.html\">Blog</a>"));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    // Test that symlinks follow the configured policy and restricted pages are left out
+    #[cfg(unix)]
+    #[test]
+    fn test_export_symlinks_and_access() {
+        let base = std::env::temp_dir().join(format!("geser-export-links-{}", std::process::id()));
+        let pages_dir = base.join("pages");
+        fs::create_dir_all(pages_dir.join("blog")).unwrap();
+        fs::create_dir_all(pages_dir.join("members")).unwrap();
+        fs::create_dir_all(base.join("outside")).unwrap();
+        fs::write(pages_dir.join("blog/first.md"), "# First post").unwrap();
+        fs::write(pages_dir.join("members/index.md"), "# Members only").unwrap();
+        fs::write(pages_dir.join("members").join(access::ACCESS_FILE), "require_cert = true\n").unwrap();
+        fs::write(pages_dir.join("secret.md"), "# Secret").unwrap();
+        fs::write(base.join("outside/leak.md"), "# Leak").unwrap();
+        std::os::unix::fs::symlink(pages_dir.join("blog"), pages_dir.join("alias")).unwrap();
+        std::os::unix::fs::symlink(&pages_dir, pages_dir.join("blog/loop")).unwrap();
+        std::os::unix::fs::symlink(base.join("outside"), pages_dir.join("outside")).unwrap();
+
+        let settings = Settings {
+            pages_dir: pages_dir.to_string_lossy().to_string(),
+            access: vec![crate::config::AccessRule { prefix: "/secret".to_string(), require_cert: true, ..Default::default() }],
+            ..Settings::default()
+        };
+        let options = ExportOptions { output_dir: base.join("public"), html_dir: None };
+        let report = export_site(&settings, &options).unwrap();
+
+        assert!(base.join("public/alias/first.gmi").exists());
+        assert!(base.join("public/blog/first.gmi").exists());
+        assert!(!base.join("public/outside").exists());
+        assert!(!base.join("public/members").exists());
+        assert!(!base.join("public/secret.gmi").exists());
+        assert!(report.warnings.iter().any(|w| w.starts_with("/outside: not exported")));
+        assert!(report.warnings.iter().any(|w| w == "/members/: not exported, access is restricted"));
+        assert!(report.warnings.iter().any(|w| w == "/secret: not exported, access is restricted"));
+        assert!(report.warnings.iter().any(|w| w == "/blog/loop/: not exported, symlink loop"));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader as AsyncBufReader};
+use tokio::net::TcpListener;
+use anyhow::{Result, anyhow};
+use url::Url;
+
+use crate::pages;
+use crate::util;
+use crate::access;
+use crate::config::{GopherSettings, Settings, SharedSettings};
+use crate::cache::Cache;
+use crate::resolve::{resolve_file, sanitize_path, SymlinkPolicy};
+
+/// Returns the Gopher item type for a sanitized path inside the capsule.
+/// Pages are served as menus, so anything that is not a static asset is type `1`.
+pub fn item_type(safe_path: &str) -> char {
+    if !pages::is_static_asset(safe_path) {
+        return '1';
+    }
+    match pages::get_mime_type(safe_path) {
+        "image/gif" => 'g',
+        mime if mime.starts_with("image/") => 'I',
+        _ => '9',
+    }
+}
+
+/// Formats a single menu line: type and display string, selector, host and port.
+fn menu_item(kind: char, display: &str, selector: &str, settings: &GopherSettings) -> String {
+    format!(
+        "{}{}\t{}\t{}\t{}\r\n",
+        kind,
+        display.replace('\t', "    "),
+        selector,
+        settings.hostname,
+        settings.port
+    )
+}
+
+/// Turns a gemtext link into a menu item. Relative links stay inside the capsule,
+/// `gopher://` links point at their own server, and everything else becomes a `URL:` link.
+fn link_item(safe_path: &str, target: &str, text: &str, prompt: bool, settings: &GopherSettings) -> String {
+    let display = if text.is_empty() { target } else { text };
+    match Url::parse(target) {
+        Err(url::ParseError::RelativeUrlWithoutBase) => {
+            let base = Url::parse(&format!("gemini://localhost{}", safe_path));
+            match base.and_then(|base| base.join(target)) {
+                Ok(url) => {
+                    let kind = if prompt { '7' } else { item_type(url.path()) };
+                    menu_item(kind, display, url.path(), settings)
+                },
+                Err(_) => menu_item('i', display, "", settings),
+            }
+        },
+        Ok(url) if url.scheme() == "gopher" => {
+            let path = url.path().strip_prefix('/').unwrap_or("");
+            let mut chars = path.chars();
+            let kind = chars.next().unwrap_or('1');
+            format!(
+                "{}{}\t{}\t{}\t{}\r\n",
+                kind,
+                display.replace('\t', "    "),
+                chars.as_str(),
+                url.host_str().unwrap_or(&settings.hostname),
+                url.port().unwrap_or(70)
+            )
+        },
+        _ => menu_item('h', display, &format!("URL:{}", target), settings),
+    }
+}
+
+/// Converts gemtext into a gophermap: text becomes info lines and link lines become menu items.
+/// `=:` prompt lines become search items (type 7), Gopher's way of asking for input.
+pub fn gemtext_to_gophermap(gemtext: &str, safe_path: &str, settings: &GopherSettings) -> String {
+    let mut output = String::new();
+    for line in gemtext.lines() {
+        let link = line.strip_prefix("=>").map(|rest| (rest, false))
+            .or_else(|| line.strip_prefix("=:").map(|rest| (rest, true)));
+        match link {
+            Some((rest, prompt)) => {
+                let rest = rest.trim();
+                let (target, text) = match rest.split_once(char::is_whitespace) {
+                    Some((target, text)) => (target, text.trim()),
+                    None => (rest, ""),
+                };
+                if !target.is_empty() {
+                    output.push_str(&link_item(safe_path, target, text, prompt, settings));
+                }
+            },
+            // Preformatted toggles have no meaning in a menu.
+            None if line.starts_with("```") => {},
+            None => output.push_str(&menu_item('i', line, "", settings)),
+        }
+    }
+    output.push_str(".\r\n");
+    output
+}
+
+/// Builds a gophermap listing a directory inside pages_dir: subdirectories and pages as menus,
+/// static assets with their item types. Hidden files and files the capsule cannot serve are skipped.
+/// The directory is resolved against pages_dir with the same symlink policy as pages.
+pub async fn directory_menu(pages_dir: &str, safe_path: &str, settings: &GopherSettings, symlinks: SymlinkPolicy) -> Result<String> {
+    let root = pages_dir.to_string();
+    let relative = safe_path.to_string();
+    let dir_path = tokio::task::spawn_blocking(move || resolve_file(std::path::Path::new(&root), &relative, symlinks)).await?
+        .map_err(|e| anyhow!("Cannot list {}: {}", safe_path, e))?;
+    let mut entries = fs::read_dir(&dir_path).await
+        .map_err(|e| anyhow!("Failed to read directory {}: {:?}", dir_path.display(), e))?;
+    let prefix = if safe_path.ends_with('/') { safe_path.to_string() } else { format!("{}/", safe_path) };
+
+    let mut items = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        if entry.file_type().await?.is_dir() {
+            items.push(('1', name.clone(), format!("{}{}", prefix, name)));
+        } else if let Some(page) = name.strip_suffix(".md") {
+            let selector = if page == "index" { prefix.clone() } else { format!("{}{}", prefix, page) };
+            items.push(('1', page.to_string(), selector));
+        } else if pages::is_static_asset(&name) {
+            let selector = format!("{}{}", prefix, name);
+            items.push((item_type(&selector), name, selector));
+        }
+    }
+    items.sort_by(|a, b| a.1.cmp(&b.1));
+
+    let mut output = menu_item('i', &format!("Index of {}", safe_path), "", settings);
+    for (kind, display, selector) in items {
+        output.push_str(&menu_item(kind, &display, &selector, settings));
+    }
+    output.push_str(".\r\n");
+    Ok(output)
+}
+
+/// Starts the Gopher gateway, serving the same pages as the Gemini listener.
+pub async fn run_gopher_server(listener: TcpListener, settings: SharedSettings, cache: Cache) -> Result<()> {
+    let address = listener.local_addr()?;
+    tracing::info!("Gopher Server started, listening on: {}", address);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        // Each connection uses the settings that are current when it is accepted
+        let settings = settings.borrow().clone();
+        let cache = cache.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_gopher_connection(stream, peer, settings, cache).await {
+                tracing::error!("Error handling Gopher connection {}: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+/// Handles a single Gopher connection: reads the selector and serves the page as a menu,
+/// a directory as a listing, or a static file as-is.
+async fn handle_gopher_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    peer: SocketAddr,
+    settings: Arc<Settings>,
+    cache: Cache,
+) -> Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut buf_reader = AsyncBufReader::new(reader);
+    let mut request_line = String::new();
+
+    let request_timeout = Duration::from_secs(settings.limits.request_timeout_secs);
+    let bytes_read = util::read_request_line(&mut buf_reader, &mut request_line, request_timeout).await
+        .map_err(|e| anyhow!("Invalid Gopher request from {}: {}", peer, e))?;
+    if bytes_read == 0 {
+        tracing::info!("Gopher connection {} closed", peer);
+        return Ok(());
+    }
+    tracing::info!("Received Gopher request from {}: {}", peer, request_line.trim_end());
+
+    // Anything after a tab is a search string, handed to the page as its input.
+    let mut fields = request_line.trim_end_matches(['\r', '\n']).split('\t');
+    let selector = fields.next().unwrap_or("");
+    let search = fields.next();
+    let selector = if selector.is_empty() { "/" } else { selector };
+    let gopher = &settings.gopher;
+
+    // Selectors are paths below pages_dir, so they must be absolute
+    if !selector.starts_with('/') {
+        writer.write_all(menu_item('3', "Invalid selector", "", gopher).as_bytes()).await?;
+        writer.write_all(b".\r\n").await?;
+        writer.flush().await?;
+        return Ok(());
+    }
+
+    let safe_path = match sanitize_path(selector, settings.paths.allow_hidden) {
+        Ok(safe_path) => safe_path,
+        Err(e) => {
+            writer.write_all(menu_item('3', &e.to_string(), "", gopher).as_bytes()).await?;
+            writer.write_all(b".\r\n").await?;
+            writer.flush().await?;
+            return Ok(());
+        }
+    };
+
+    // Gopher has no client certificates, so paths that need one are refused
+    let refusal = match access::check(&settings.access, &settings.pages_dir, &safe_path, settings.paths.symlinks, Some(peer.ip()), None, None).await {
+        Ok(verdict) => verdict.reason(),
+        Err(e) => {
+            tracing::error!("Error checking access to {}: {:?}", safe_path, e);
+            Some("Access rules unavailable")
+        }
+    };
+    if let Some(reason) = refusal {
+        writer.write_all(menu_item('3', reason, "", gopher).as_bytes()).await?;
+        writer.write_all(b".\r\n").await?;
+        writer.flush().await?;
+        return Ok(());
+    }
+
+    match pages::lookup(&settings.pages_dir, &safe_path, cache, settings.paths.symlinks).await {
+        Ok(pages::Page::Static(data, _mime)) => {
+            writer.write_all(&data).await?;
+        },
+        Ok(pages::Page::Gemtext(content)) => {
+            writer.write_all(gemtext_to_gophermap(&pages::fill_input(&pages::fill_username(&content, None), search), &safe_path, gopher).as_bytes()).await?;
+        },
+        Err(e) => match directory_menu(&settings.pages_dir, &safe_path, gopher, settings.paths.symlinks).await {
+            Ok(menu) => writer.write_all(menu.as_bytes()).await?,
+            Err(_) => {
+                tracing::error!("Error serving {} over Gopher: {:?}", safe_path, e);
+                writer.write_all(menu_item('3', "Not Found", "", gopher).as_bytes()).await?;
+                writer.write_all(b".\r\n").await?;
+            }
+        },
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+    use std::path::Path;
+    use tokio::io::AsyncReadExt;
+    use crate::config::AccessRule;
+
+    fn settings() -> GopherSettings {
+        GopherSettings {
+            hostname: "example.org".to_string(),
+            ..GopherSettings::default()
+        }
+    }
+
+    // Test item types for pages and static files
+    #[test]
+    fn test_item_type() {
+        assert_eq!(item_type("/about"), '1');
+        assert_eq!(item_type("/images/a.png"), 'I');
+        assert_eq!(item_type("/images/a.gif"), 'g');
+    }
+
+    // Test converting gemtext into a gophermap
+    #[test]
+    fn test_gemtext_to_gophermap() {
+        let gemtext = "# Blog\n=> post First post\n=> /images/a.png\n=: /search Search\n=> gopher://other.org:7070/0/notes.txt Notes\n=> https://example.com Web";
+        let map = gemtext_to_gophermap(gemtext, "/blog/", &settings());
+        assert_eq!(map, concat!(
+            "i# Blog\t\texample.org\t70\r\n",
+            "1First post\t/blog/post\texample.org\t70\r\n",
+            "I/images/a.png\t/images/a.png\texample.org\t70\r\n",
+            "7Search\t/search\texample.org\t70\r\n",
+            "0Notes\t/notes.txt\tother.org\t7070\r\n",
+            "hWeb\tURL:https://example.com\texample.org\t70\r\n",
+            ".\r\n",
+        ));
+    }
+
+    // Test that selectors are capped in length and must arrive in time
+    #[tokio::test]
+    async fn test_gopher_request_limits() {
+        let peer: SocketAddr = "127.0.0.1:70".parse().unwrap();
+        let (mut client, server) = tokio::io::duplex(4096);
+        let task = tokio::spawn(handle_gopher_connection(server, peer, Arc::new(Settings::default()), Cache::new()));
+        client.write_all(&[b'/'; 2048]).await.unwrap();
+        assert!(task.await.unwrap().is_err(), "Overlong selectors are refused");
+
+        let mut settings = Settings::default();
+        settings.limits.request_timeout_secs = 1;
+        let (_client, server) = tokio::io::duplex(4096);
+        let result = tokio::time::timeout(Duration::from_secs(3), handle_gopher_connection(server, peer, Arc::new(settings), Cache::new())).await;
+        assert!(result.expect("Idle clients are timed out").is_err());
+    }
+
+    // Test directory listings and serving over an in-memory stream
+    #[tokio::test]
+    async fn test_gopher_directory_and_page() {
+        let pages_dir = std::env::temp_dir().join(format!("geser-gopher-{}", std::process::id()));
+        fs::create_dir_all(pages_dir.join("blog")).await.unwrap();
+        fs::write(pages_dir.join("blog/index.md"), "# Blog").await.unwrap();
+        fs::write(pages_dir.join("blog/post.md"), "[Home](/)").await.unwrap();
+        fs::write(pages_dir.join("blog/photo.png"), [1, 2, 3]).await.unwrap();
+        fs::write(pages_dir.join("blog/.secret"), "hidden").await.unwrap();
+        let pages_dir = pages_dir.to_string_lossy().to_string();
+
+        let menu = directory_menu(&pages_dir, "/blog", &settings(), SymlinkPolicy::Inside).await.unwrap();
+        assert_eq!(menu, concat!(
+            "iIndex of /blog\t\texample.org\t70\r\n",
+            "1index\t/blog/\texample.org\t70\r\n",
+            "Iphoto.png\t/blog/photo.png\texample.org\t70\r\n",
+            "1post\t/blog/post\texample.org\t70\r\n",
+            ".\r\n",
+        ));
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        let settings = Settings {
+            pages_dir: pages_dir.clone(),
+            gopher: self::settings(),
+            ..Settings::default()
+        };
+        let peer: SocketAddr = "127.0.0.1:70".parse().unwrap();
+        let task = tokio::spawn(handle_gopher_connection(server, peer, Arc::new(settings), Cache::new()));
+        client.write_all(b"/blog/post\r\n").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        task.await.unwrap().unwrap();
+        assert!(response.contains("1Home\t/\texample.org\t70\r\n"));
+        assert!(response.ends_with(".\r\n"));
+
+        // A selector without a leading slash would otherwise be appended to the pages_dir name
+        fs::write(format!("{}-private.md", pages_dir), "# Private").await.unwrap();
+        let (mut client, server) = tokio::io::duplex(4096);
+        let settings = Settings { pages_dir: pages_dir.clone(), gopher: self::settings(), ..Settings::default() };
+        let task = tokio::spawn(handle_gopher_connection(server, peer, Arc::new(settings), Cache::new()));
+        client.write_all(b"-private\r\n").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        task.await.unwrap().unwrap();
+        assert!(response.starts_with("3Invalid selector"), "Relative selectors are refused");
+        fs::remove_file(format!("{}-private.md", pages_dir)).await.unwrap();
+
+        // Without client certificates, paths that need one are refused
+        fs::write(Path::new(&pages_dir).join("blog").join(access::ACCESS_FILE), "require_cert = true\n").await.unwrap();
+        let club = vec![AccessRule { prefix: "/".to_string(), users: vec!["alice".to_string()], ..AccessRule::default() }];
+        for (rules, selector) in [(Vec::new(), "/blog/post"), (club, "/")] {
+            let (mut client, server) = tokio::io::duplex(4096);
+            let settings = Settings { pages_dir: pages_dir.clone(), gopher: self::settings(), access: rules, ..Settings::default() };
+            let task = tokio::spawn(handle_gopher_connection(server, peer, Arc::new(settings), Cache::new()));
+            client.write_all(format!("{}\r\n", selector).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            task.await.unwrap().unwrap();
+            assert!(response.starts_with("3Client certificate required"), "{} should be refused, got {:?}", selector, response);
+        }
+
+        fs::remove_dir_all(&pages_dir).await.unwrap();
+    }
+}
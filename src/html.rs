@@ -0,0 +1,151 @@
+use url::Url;
+
+/// Escapes text for inclusion in HTML element content and attribute values.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Rewrites a link target for the web: `gemini://` links to one of the capsule's own hostnames
+/// become HTTP paths, everything else is left untouched.
+pub fn rewrite_link(target: &str, hostnames: &[String]) -> String {
+    match Url::parse(target) {
+        Ok(url) if url.scheme() == "gemini"
+            && url.host_str().is_some_and(|host| hostnames.iter().any(|h| h.eq_ignore_ascii_case(host))) => {
+            match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_string(),
+            }
+        },
+        _ => target.to_string(),
+    }
+}
+
+/// Converts gemtext into simple semantic HTML. Each line type maps to one element:
+/// headings, links (and `=:` prompts), list items, quotes, preformatted blocks and paragraphs.
+pub fn gemtext_to_html(gemtext: &str, hostnames: &[String]) -> String {
+    let mut output = String::new();
+    let mut in_list = false;
+    let mut in_pre = false;
+
+    for line in gemtext.lines() {
+        if in_pre {
+            if line.starts_with("```") {
+                output.push_str("</pre>\n");
+                in_pre = false;
+            } else {
+                output.push_str(&escape(line));
+                output.push('\n');
+            }
+            continue;
+        }
+
+        let is_list_item = line.starts_with("* ");
+        if in_list && !is_list_item {
+            output.push_str("</ul>\n");
+            in_list = false;
+        }
+
+        if line.starts_with("```") {
+            output.push_str("<pre>");
+            in_pre = true;
+        } else if let Some(item) = line.strip_prefix("* ") {
+            if !in_list {
+                output.push_str("<ul>\n");
+                in_list = true;
+            }
+            output.push_str(&format!("<li>{}</li>\n", escape(item.trim())));
+        } else if let Some(rest) = line.strip_prefix("=>").or_else(|| line.strip_prefix("=:")) {
+            let rest = rest.trim();
+            let (target, text) = match rest.split_once(char::is_whitespace) {
+                Some((target, text)) => (target, text.trim()),
+                None => (rest, rest),
+            };
+            if !target.is_empty() {
+                output.push_str(&format!(
+                    "<p><a href=\"{}\">{}</a></p>\n",
+                    escape(&rewrite_link(target, hostnames)),
+                    escape(text)
+                ));
+            }
+        } else if let Some(heading) = line.strip_prefix("###") {
+            output.push_str(&format!("<h3>{}</h3>\n", escape(heading.trim())));
+        } else if let Some(heading) = line.strip_prefix("##") {
+            output.push_str(&format!("<h2>{}</h2>\n", escape(heading.trim())));
+        } else if let Some(heading) = line.strip_prefix('#') {
+            output.push_str(&format!("<h1>{}</h1>\n", escape(heading.trim())));
+        } else if let Some(quote) = line.strip_prefix('>') {
+            output.push_str(&format!("<blockquote>{}</blockquote>\n", escape(quote.trim())));
+        } else if !line.trim().is_empty() {
+            output.push_str(&format!("<p>{}</p>\n", escape(line)));
+        }
+    }
+    if in_list {
+        output.push_str("</ul>\n");
+    }
+    if in_pre {
+        output.push_str("</pre>\n");
+    }
+    output
+}
+
+/// Wraps converted HTML in a complete document. The title is the first heading, if any.
+pub fn html_document(gemtext: &str, hostnames: &[String]) -> String {
+    let title = gemtext.lines()
+        .find_map(|line| line.strip_prefix('#'))
+        .map(|heading| heading.trim_start_matches('#').trim().to_string())
+        .unwrap_or_default();
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<main>\n{}</main>\n</body>\n</html>\n",
+        escape(&title),
+        gemtext_to_html(gemtext, hostnames)
+    )
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+
+    // Test that each gemtext line type is converted
+    #[test]
+    fn test_gemtext_to_html() {
+        let gemtext = "# Title\n\nSome <text>\n* one\n* two\n=> /about About\n> quoted\n```\nfn main() {}\n```\n## Sub";
+        assert_eq!(gemtext_to_html(gemtext, &[]), concat!(
+            "<h1>Title</h1>\n",
+            "<p>Some &lt;text&gt;</p>\n",
+            "<ul>\n<li>one</li>\n<li>two</li>\n</ul>\n",
+            "<p><a href=\"/about\">About</a></p>\n",
+            "<blockquote>quoted</blockquote>\n",
+            "<pre>fn main() {}\n</pre>\n",
+            "<h2>Sub</h2>\n",
+        ));
+    }
+
+    // Test rewriting of gemini links to the capsule's own hosts
+    #[test]
+    fn test_rewrite_link() {
+        let hostnames = vec!["example.org".to_string()];
+        assert_eq!(rewrite_link("gemini://example.org/blog/post", &hostnames), "/blog/post");
+        assert_eq!(rewrite_link("gemini://example.org/search?q", &hostnames), "/search?q");
+        assert_eq!(rewrite_link("gemini://other.org/", &hostnames), "gemini://other.org/");
+        assert_eq!(rewrite_link("/relative", &hostnames), "/relative");
+    }
+
+    // Test the document title comes from the first heading
+    #[test]
+    fn test_html_document_title() {
+        let document = html_document("## Hello & welcome\ntext", &[]);
+        assert!(document.contains("<title>Hello &amp; welcome</title>"));
+    }
+}
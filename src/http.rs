@@ -0,0 +1,177 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader as AsyncBufReader};
+use tokio::net::TcpListener;
+use anyhow::Result;
+use percent_encoding::percent_decode_str;
+
+use crate::html;
+use crate::access;
+use crate::pages;
+use crate::config::{Settings, SharedSettings};
+use crate::cache::Cache;
+use crate::resolve::{sanitize_path, PathError};
+
+/// Starts the plain-HTTP preview listener, serving the capsule as HTML.
+pub async fn run_http_server(listener: TcpListener, settings: SharedSettings, cache: Cache) -> Result<()> {
+    let address = listener.local_addr()?;
+    tracing::info!("HTTP preview server started, listening on: http://{}", address);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        // Each connection uses the settings that are current when it is accepted
+        let settings = settings.borrow().clone();
+        let cache = cache.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_http_connection(stream, peer, settings, cache).await {
+                tracing::error!("Error handling HTTP connection {}: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+/// Writes a complete HTTP/1.1 response and closes the connection.
+pub async fn write_response<W: AsyncWrite + Unpin>(writer: &mut W, status: &str, content_type: &str, body: &[u8]) -> Result<()> {
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    writer.write_all(header.as_bytes()).await?;
+    writer.write_all(body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Handles a single HTTP request: only `GET` is supported, pages are rendered to HTML
+/// and static files are served with their MIME type.
+async fn handle_http_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    peer: SocketAddr,
+    settings: Arc<Settings>,
+    cache: Cache,
+) -> Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut buf_reader = AsyncBufReader::new(reader);
+    let mut request_line = String::new();
+
+    if buf_reader.read_line(&mut request_line).await? == 0 {
+        return Ok(());
+    }
+    // Skip the request headers; none of them change the response.
+    loop {
+        let mut header = String::new();
+        if buf_reader.read_line(&mut header).await? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+    tracing::info!("Received HTTP request from {}: {}", peer, request_line.trim_end());
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return write_response(&mut writer, "400 Bad Request", "text/plain", b"Bad Request").await,
+    };
+    if method != "GET" {
+        return write_response(&mut writer, "405 Method Not Allowed", "text/plain", b"Method Not Allowed").await;
+    }
+
+    // Only origin-form targets are paths below pages_dir
+    let path = target.split('?').next().unwrap_or("/");
+    if !path.starts_with('/') {
+        return write_response(&mut writer, "400 Bad Request", "text/plain", b"Bad Request").await;
+    }
+    let safe_path = match sanitize_path(path, settings.paths.allow_hidden) {
+        Ok(safe_path) => safe_path,
+        Err(PathError::Hidden) => return write_response(&mut writer, "404 Not Found", "text/plain", b"Not Found").await,
+        Err(PathError::Invalid(_)) => return write_response(&mut writer, "400 Bad Request", "text/plain", b"Bad Request").await,
+    };
+
+    // HTTP clients present no Gemini certificates, so paths that need one are refused
+    match access::check(&settings.access, &settings.pages_dir, &safe_path, settings.paths.symlinks, Some(peer.ip()), None, None).await {
+        Ok(verdict) => if let Some(reason) = verdict.reason() {
+            return write_response(&mut writer, "403 Forbidden", "text/plain", reason.as_bytes()).await;
+        },
+        Err(e) => {
+            tracing::error!("Error checking access to {}: {:?}", safe_path, e);
+            return write_response(&mut writer, "500 Internal Server Error", "text/plain", b"Access rules unavailable").await;
+        }
+    }
+
+    match pages::lookup(&settings.pages_dir, &safe_path, cache, settings.paths.symlinks).await {
+        Ok(pages::Page::Static(data, mime)) => {
+            write_response(&mut writer, "200 OK", mime, &data).await
+        },
+        Ok(pages::Page::Gemtext(content)) => {
+            let query = target.split_once('?').map(|(_, query)| percent_decode_str(query).decode_utf8_lossy());
+            let content = pages::fill_input(&pages::fill_username(&content, None), query.as_deref());
+            let document = html::html_document(&content, &settings.hostnames);
+            write_response(&mut writer, "200 OK", "text/html; charset=utf-8", document.as_bytes()).await
+        },
+        Err(e) => {
+            tracing::error!("Error serving {} over HTTP: {:?}", safe_path, e);
+            write_response(&mut writer, "404 Not Found", "text/plain", b"Not Found").await
+        }
+    }
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+    use tokio::fs;
+    use tokio::io::AsyncReadExt;
+    use crate::config::AccessRule;
+
+    // Send a raw request over an in-memory stream and return the full response
+    async fn request(settings: Arc<Settings>, raw: &[u8]) -> String {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let peer: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let task = tokio::spawn(handle_http_connection(server, peer, settings, Cache::new()));
+        client.write_all(raw).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        task.await.unwrap().unwrap();
+        response
+    }
+
+    // Test rendering a page as HTML with capsule links rewritten
+    #[tokio::test]
+    async fn test_http_preview() {
+        let pages_dir = std::env::temp_dir().join(format!("geser-http-{}", std::process::id()));
+        fs::create_dir_all(&pages_dir).await.unwrap();
+        fs::write(pages_dir.join("index.md"), "# Home\n\n[Post](gemini://example.org/post)").await.unwrap();
+        let settings = Arc::new(Settings {
+            pages_dir: pages_dir.to_string_lossy().to_string(),
+            hostnames: vec!["example.org".to_string()],
+            ..Settings::default()
+        });
+
+        let response = request(settings.clone(), b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n"));
+        assert!(response.contains("<h1>Home</h1>"));
+        assert!(response.contains("<a href=\"/post\">Post</a>"));
+
+        let response = request(settings.clone(), b"GET /missing HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let response = request(settings.clone(), b"GET -private HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "Targets without a leading slash are refused");
+
+        let response = request(settings, b"POST / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        // Without client certificates, paths that need one are refused
+        fs::create_dir_all(pages_dir.join("members")).await.unwrap();
+        fs::write(pages_dir.join("members/index.md"), "# Members").await.unwrap();
+        fs::write(pages_dir.join("members").join(access::ACCESS_FILE), "require_cert = true\n").await.unwrap();
+        let club = vec![AccessRule { prefix: "/".to_string(), users: vec!["alice".to_string()], ..AccessRule::default() }];
+        for (rules, target) in [(Vec::new(), "/members/"), (club, "/")] {
+            let settings = Arc::new(Settings { pages_dir: pages_dir.to_string_lossy().to_string(), access: rules, ..Settings::default() });
+            let response = request(settings, format!("GET {} HTTP/1.1\r\n\r\n", target).as_bytes()).await;
+            assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{} should be refused, got {:?}", target, response);
+        }
+        fs::remove_dir_all(&pages_dir).await.unwrap();
+    }
+}
@@ -0,0 +1,347 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use anyhow::{Result, anyhow};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::config::Settings;
+use crate::systemd::PassedSocket;
+
+/// Backlog of pending connections on each TCP listener.
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Where a client connected from, as reported by the listener that accepted it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Peer {
+    Ip(SocketAddr),
+    /// A client on the Unix socket, which has no address of its own.
+    Unix,
+}
+
+impl Peer {
+    /// The client's IP address, or `None` if it is unknown. Address rules fail closed on `None`.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Ip(address) => Some(address.ip()),
+            Peer::Unix => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Ip(address) => write!(f, "{}", address),
+            Peer::Unix => write!(f, "unix socket"),
+        }
+    }
+}
+
+/// How the connections on a listener are secured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    /// Clients connect with TLS, which the server terminates.
+    Tls,
+    /// Plaintext Gemini from a trusted proxy that terminates TLS (the `[upstream]` listener).
+    Upstream,
+}
+
+/// A connection accepted by one of the Gemini listeners.
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// One bound listening socket.
+enum Listener {
+    Tcp(TcpListener),
+    /// A Unix domain socket and its path.
+    #[cfg(unix)]
+    Unix(UnixListener, String),
+}
+
+impl Listener {
+    fn poll_accept(&self, cx: &mut TaskContext<'_>) -> Poll<io::Result<(Connection, Peer)>> {
+        match self {
+            Listener::Tcp(listener) => listener.poll_accept(cx)
+                .map_ok(|(stream, peer)| (Connection::Tcp(stream), Peer::Ip(peer))),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.poll_accept(cx)
+                .map_ok(|(stream, _)| (Connection::Unix(stream), Peer::Unix)),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map_or_else(|_| "unknown address".to_string(), |addr| addr.to_string()),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => format!("unix:{}", path),
+        }
+    }
+}
+
+/// All sockets the Gemini listener accepts connections on: `address`, the `listen` addresses
+/// and the optional `unix_socket`, or the sockets passed by systemd socket activation.
+pub struct Listeners {
+    listeners: Vec<(Listener, Transport)>,
+    // Listener to poll first, so that a busy listener cannot starve the others
+    next: usize,
+    // Unix socket files created by the server, removed again when the listeners are dropped
+    socket_files: Vec<String>,
+}
+
+impl Listeners {
+    // Binds every configured address, failing if any of them cannot be bound.
+    // When started through systemd socket activation, the `passed` sockets are used instead.
+    pub async fn bind(settings: &Settings, passed: Vec<PassedSocket>) -> Result<Self> {
+        if !passed.is_empty() {
+            tracing::info!("Using {} socket(s) passed by systemd instead of the configured addresses", passed.len());
+            return Self::from_fds(passed);
+        }
+        let mut listeners = Vec::new();
+        for address in std::iter::once(&settings.address).chain(&settings.listen) {
+            listeners.push((Listener::Tcp(bind_tcp(address, settings.ipv6_only).await?), Transport::Tls));
+        }
+        if let Some(path) = &settings.unix_socket {
+            listeners.push((bind_unix(path)?, Transport::Tls));
+        }
+        if settings.upstream.enabled {
+            let listener = match &settings.upstream.unix_socket {
+                Some(path) => bind_unix(path)?,
+                None => Listener::Tcp(bind_tcp(&settings.upstream.address, settings.ipv6_only).await?),
+            };
+            listeners.push((listener, Transport::Upstream));
+        }
+        let socket_files = listeners.iter()
+            .filter_map(|(listener, _)| match listener {
+                #[cfg(unix)]
+                Listener::Unix(_, path) => Some(path.clone()),
+                _ => None,
+            })
+            .collect();
+        Ok(Listeners { listeners, next: 0, socket_files })
+    }
+
+    // Takes over already listening TCP or Unix stream sockets, such as those passed by systemd.
+    // A socket named `upstream` is used as the plaintext upstream listener.
+    #[cfg(unix)]
+    fn from_fds(fds: Vec<PassedSocket>) -> Result<Self> {
+        let mut listeners = Vec::new();
+        for (fd, name) in fds {
+            let transport = if name == "upstream" { Transport::Upstream } else { Transport::Tls };
+            let socket = Socket::from(fd);
+            if socket.r#type()? != Type::STREAM {
+                return Err(anyhow!("Passed socket {:?} is not a stream socket", socket.local_addr()?));
+            }
+            socket.set_nonblocking(true)?;
+            let address = socket.local_addr()?;
+            if address.as_socket().is_some() {
+                listeners.push((Listener::Tcp(TcpListener::from_std(socket.into())?), transport));
+            } else {
+                let listener = std::os::unix::net::UnixListener::from(std::os::fd::OwnedFd::from(socket));
+                let path = address.as_pathname().map_or_else(|| "unnamed".to_string(), |path| path.display().to_string());
+                listeners.push((Listener::Unix(UnixListener::from_std(listener)?, path), transport));
+            }
+        }
+        Ok(Listeners { listeners, next: 0, socket_files: Vec::new() })
+    }
+
+    // No sockets are ever passed where socket activation does not exist
+    #[cfg(not(unix))]
+    fn from_fds(fds: Vec<PassedSocket>) -> Result<Self> {
+        match fds.into_iter().next() {
+            Some((never, _)) => match never {},
+            None => Ok(Listeners { listeners: Vec::new(), next: 0, socket_files: Vec::new() }),
+        }
+    }
+
+    // Human-readable addresses of the listeners, for logging
+    pub fn describe(&self) -> Vec<String> {
+        self.listeners.iter()
+            .map(|(listener, transport)| match transport {
+                Transport::Tls => listener.describe(),
+                Transport::Upstream => format!("{} (upstream)", listener.describe()),
+            })
+            .collect()
+    }
+
+    // Accepts the next connection on any of the listeners
+    pub async fn accept(&mut self) -> io::Result<(Connection, Peer, Transport)> {
+        std::future::poll_fn(|cx| {
+            let count = self.listeners.len();
+            for offset in 0..count {
+                let index = (self.next + offset) % count;
+                let (listener, transport) = &self.listeners[index];
+                if let Poll::Ready(result) = listener.poll_accept(cx) {
+                    let transport = *transport;
+                    self.next = (index + 1) % count;
+                    return Poll::Ready(result.map(|(connection, peer)| (connection, peer, transport)));
+                }
+            }
+            Poll::Pending
+        }).await
+    }
+}
+
+impl Drop for Listeners {
+    fn drop(&mut self) {
+        for path in &self.socket_files {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// Binds a TCP listener on the first resolved address that can be bound, like `TcpListener::bind`,
+// but setting IPV6_V6ONLY on IPv6 sockets as configured
+async fn bind_tcp(address: &str, ipv6_only: bool) -> Result<TcpListener> {
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host(address).await
+        .map_err(|e| anyhow!("Failed to resolve address {}: {:?}", address, e))?
+    {
+        match bind_socket(addr, ipv6_only) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(anyhow!("Failed to bind to address {}: {:?}", address, last_error))
+}
+
+fn bind_socket(addr: SocketAddr, ipv6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+// Binds a Unix domain socket, replacing a socket file left behind by a previous run
+#[cfg(unix)]
+fn bind_unix(path: &str) -> Result<Listener> {
+    use std::os::unix::fs::FileTypeExt;
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|e| anyhow!("Failed to bind Unix socket {}: {:?}", path, e))?;
+    Ok(Listener::Unix(listener, path.to_string()))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(path: &str) -> Result<Listener> {
+    Err(anyhow!("Cannot listen on Unix socket {}: Unix sockets are not supported on this platform", path))
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Test accepting connections on several TCP addresses and a Unix socket
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_multiple_listeners() {
+        let socket_path = std::env::temp_dir().join(format!("geser-listener-{}.sock", std::process::id()));
+        let settings = Settings {
+            address: "127.0.0.1:0".to_string(),
+            listen: vec!["127.0.0.1:0".to_string()],
+            unix_socket: Some(socket_path.to_string_lossy().to_string()),
+            ..Settings::default()
+        };
+        let mut listeners = Listeners::bind(&settings, Vec::new()).await.unwrap();
+        let addresses = listeners.describe();
+        assert_eq!(addresses.len(), 3);
+        assert_eq!(addresses[2], format!("unix:{}", socket_path.display()));
+
+        for address in &addresses[..2] {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            let (mut connection, peer, transport) = listeners.accept().await.unwrap();
+            assert_eq!(transport, Transport::Tls);
+            assert_eq!(peer.ip(), Some(client.local_addr().unwrap().ip()));
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            connection.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+        }
+        let _client = UnixStream::connect(&socket_path).await.unwrap();
+        let (_, peer, _) = listeners.accept().await.unwrap();
+        assert_eq!(peer, Peer::Unix);
+        assert_eq!(peer.ip(), None, "Unix socket clients have no address for the access rules to match");
+
+        drop(listeners);
+        assert!(!socket_path.exists(), "The socket file is removed when the listeners are dropped");
+    }
+
+    // Test taking over sockets that are already listening, as passed by systemd
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_from_fds() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp.local_addr().unwrap();
+        let mut listeners = Listeners::from_fds(vec![(tcp.into(), "upstream".to_string())]).unwrap();
+        assert_eq!(listeners.describe(), vec![format!("{} (upstream)", address)]);
+        let _client = TcpStream::connect(address).await.unwrap();
+        assert_eq!(listeners.accept().await.unwrap().2, Transport::Upstream, "Sockets named upstream are plaintext");
+
+        let datagram = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(Listeners::from_fds(vec![(datagram.into(), "unknown".to_string())]).is_err(), "Only stream sockets can be listened on");
+    }
+
+    // Test that IPV6_V6ONLY lets an IPv4 listener share the port with an IPv6 wildcard listener
+    #[tokio::test]
+    async fn test_ipv6_only() {
+        let Ok(ipv6) = bind_tcp("[::]:0", true).await else {
+            return; // No IPv6 in this environment
+        };
+        let port = ipv6.local_addr().unwrap().port();
+        assert!(bind_tcp(&format!("0.0.0.0:{}", port), true).await.is_ok());
+    }
+}
@@ -4,44 +4,153 @@ mod tls;
 mod pages;
 mod cache;
 mod util;
+mod titan;
+mod spartan;
+mod gopher;
+mod html;
+mod http;
+mod export;
+mod cli;
+mod certinfo;
+mod metrics;
+mod ratelimit;
+mod accesslog;
+mod admin;
+mod listener;
+mod systemd;
+mod privileges;
+mod proxy;
+mod access;
+mod registration;
+mod resolve;
 
-use anyhow::Result;
-use tracing_subscriber;
-use config::Settings;
-use tokio;
+use anyhow::{Result, anyhow};
+use clap::Parser;
+use cli::{Cli, Command};
+use config::{ConfigSource, Settings};
 
 // Main function
-#[tokio::main(flavor = "multi_thread")]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
+    // Take over the sockets passed by systemd and clear their environment variables
+    // while the process is still single-threaded, before the runtime starts its workers
+    let passed_sockets = systemd::listen_fds();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(passed_sockets))
+}
+
+// Runs the command given on the command line
+async fn run(passed_sockets: Vec<systemd::PassedSocket>) -> Result<()> {
+    let cli = Cli::parse();
+
     // Initialize logging (tracing)
-    tracing_subscriber::fmt::init();
+    tracing_subscriber::fmt().with_max_level(cli.log_level).init();
+
+    let command = cli.command.unwrap_or(Command::Serve);
+    if let Command::Version = command {
+        println!("geser {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
 
-    // Load configuration
-    let settings = Settings::new()?;
+    // Load configuration with command-line overrides, which are applied again whenever it is reloaded
+    let mut overrides = Vec::new();
+    if let Some(address) = cli.address {
+        overrides.push(("address".to_string(), address));
+    }
+    if let Some(pages_dir) = cli.pages_dir {
+        overrides.push(("pages_dir".to_string(), pages_dir));
+    }
+    let settings = ConfigSource { path: cli.config, overrides }.load()?;
     tracing::info!("Loaded settings: {:?}", settings);
 
-    // Start the Gemini server
-    server::run_server(settings).await
+    match command {
+        Command::Serve => {
+            // Refuse to start with settings a reload would be refused for
+            settings.validate()?;
+            // Generate a self-signed certificate on first start
+            tls::ensure_certificate(&settings)?;
+            // Start the Gemini server, shutting down gracefully on SIGTERM or SIGINT
+            server::run_server(settings, passed_sockets, server::shutdown_signal()).await
+        },
+        Command::CheckConfig => check_config(&settings),
+        Command::CheckTls => {
+            print!("{}", tls::tls_report(&settings.cert_path, &settings.key_path, settings.key_passphrase()?.as_deref())?);
+            Ok(())
+        },
+        Command::GenCert { force } => gen_cert(&settings, force),
+        Command::Build { output_dir, html } => build(&settings, export::ExportOptions {
+            output_dir,
+            html_dir: html,
+        }),
+        Command::Version => unreachable!("handled before loading settings"),
+    }
+}
+
+// Validates the settings and the TLS material they point to
+fn check_config(settings: &Settings) -> Result<()> {
+    settings.validate()?;
+    tls::load_tls_config(&settings.cert_path, &settings.key_path, settings.key_passphrase()?.as_deref())?;
+    println!("Configuration OK");
+    Ok(())
+}
+
+// Writes a new self-signed certificate for the configured hostnames
+fn gen_cert(settings: &Settings, force: bool) -> Result<()> {
+    if !force && (std::path::Path::new(&settings.cert_path).exists() || std::path::Path::new(&settings.key_path).exists()) {
+        return Err(anyhow!(
+            "{} or {} already exists; use --force to overwrite",
+            settings.cert_path,
+            settings.key_path
+        ));
+    }
+    let fingerprint = tls::generate_self_signed(&settings.cert_path, &settings.key_path, &settings.hostnames, &settings.self_signed)?;
+    println!("Wrote {} and {}", settings.cert_path, settings.key_path);
+    println!("SHA-256 fingerprint: {}", fingerprint);
+    Ok(())
+}
+
+// Exports the capsule as a static gemtext (and optionally HTML) tree and reports any problems found
+fn build(settings: &Settings, options: export::ExportOptions) -> Result<()> {
+    let report = export::export_site(settings, &options)?;
+    for warning in &report.warnings {
+        tracing::warn!("{}", warning);
+    }
+    tracing::info!(
+        "Exported {} pages, {} assets and {} generated indexes to {} ({} warnings)",
+        report.pages,
+        report.assets,
+        report.indexes,
+        options.output_dir.display(),
+        report.warnings.len()
+    );
+    Ok(())
 }
 
 // Test module
 #[cfg(test)]
 mod tests {
     use super::*;  // Import items from the outer module
-    use tokio;     // Import the tokio runtime
-    
+
     // Test configuration loading functionality
     #[tokio::test]
     async fn test_settings_loading() {
-        let settings = Settings::new();
+        let settings = ConfigSource::default().load();
         assert!(settings.is_ok(), "Configuration loading should succeed");
     }
 
     // Note: Since `tracing_subscriber` initialization doesn't return a value, we can't directly test the log initialization.
     #[tokio::test]
     async fn test_server_run() {
-        let settings = Settings::new().unwrap(); // Assuming that loading the configuration is successful
-        let result = server::run_server(settings).await;
+        let mut settings = ConfigSource::default().load().unwrap(); // Assuming that loading the configuration is successful
+        settings.address = "127.0.0.1:0".to_string();
+        settings.cert_path = "test_cert.pem".to_string();
+        settings.key_path = "test_key.pem".to_string();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(server::run_server(settings, Vec::new(), async { let _ = stopped.await; }));
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        stop.send(()).unwrap();
+        let result = server.await.unwrap();
         assert!(result.is_ok(), "The server should run without errors");
     }
 }
@@ -0,0 +1,240 @@
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use anyhow::Result;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader as AsyncBufReader};
+use tokio::net::TcpListener;
+
+use crate::cache::Cache;
+use crate::http::write_response;
+
+/// Upper bounds, in seconds, of the request latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+/// Process-wide counters describing what the server has been doing.
+pub struct Metrics {
+    /// TLS configurations successfully reloaded and swapped in.
+    pub tls_reloads: AtomicU64,
+    /// TLS reloads that failed, leaving the previous configuration in place.
+    pub tls_reload_failures: AtomicU64,
+    /// Configurations successfully reloaded and swapped in.
+    pub config_reloads: AtomicU64,
+    /// Configuration reloads rejected, leaving the previous settings in place.
+    pub config_reload_failures: AtomicU64,
+    /// Gemini requests by two-digit status code; index 0 counts requests that got no response.
+    pub requests: [AtomicU64; 100],
+    /// Bytes sent in Gemini responses, headers included.
+    pub response_bytes: AtomicU64,
+    /// Request latency histogram: one counter per bucket (not cumulative), then the overflow.
+    pub latency_buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    /// Sum of request latencies in microseconds.
+    pub latency_sum_micros: AtomicU64,
+    /// TLS handshakes that failed or timed out.
+    pub handshake_failures: AtomicU64,
+    /// Gemini connections currently open.
+    pub active_connections: AtomicU64,
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            tls_reloads: AtomicU64::new(0),
+            tls_reload_failures: AtomicU64::new(0),
+            config_reloads: AtomicU64::new(0),
+            config_reload_failures: AtomicU64::new(0),
+            requests: [const { AtomicU64::new(0) }; 100],
+            response_bytes: AtomicU64::new(0),
+            latency_buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len() + 1],
+            latency_sum_micros: AtomicU64::new(0),
+            handshake_failures: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+        }
+    }
+
+    // Records a finished request: its status (if a response was sent), response size and latency
+    pub fn record_request(&self, status: Option<&str>, bytes: u64, latency: Duration) {
+        let index = status.and_then(|status| status.parse::<usize>().ok()).filter(|code| *code < 100).unwrap_or(0);
+        increment(&self.requests[index]);
+        self.response_bytes.fetch_add(bytes, Ordering::Relaxed);
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(LATENCY_BUCKETS.len());
+        increment(&self.latency_buckets[bucket]);
+        self.latency_sum_micros.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self, cache: &Cache) -> String {
+        let mut out = String::new();
+        let requests: Vec<(String, u64)> = self.requests.iter().enumerate()
+            .filter_map(|(code, counter)| {
+                let value = get(counter);
+                let status = if code == 0 { "none".to_string() } else { format!("{:02}", code) };
+                (value > 0).then(|| (format!("{{status=\"{}\"}}", status), value))
+            })
+            .collect();
+        write_metric(&mut out, "geser_requests_total", "counter", "Gemini requests by response status.", &requests);
+        write_metric(&mut out, "geser_response_bytes_total", "counter", "Bytes sent in Gemini responses.", &[(String::new(), get(&self.response_bytes))]);
+
+        let mut cumulative = 0;
+        let mut latency = Vec::new();
+        for (index, counter) in self.latency_buckets.iter().enumerate() {
+            cumulative += get(counter);
+            let bound = LATENCY_BUCKETS.get(index).map_or("+Inf".to_string(), |bound| bound.to_string());
+            latency.push((format!("_bucket{{le=\"{}\"}}", bound), cumulative));
+        }
+        write_metric(&mut out, "geser_request_duration_seconds", "histogram", "Time to handle a Gemini request.", &latency);
+        let _ = writeln!(out, "geser_request_duration_seconds_sum {}", get(&self.latency_sum_micros) as f64 / 1_000_000.0);
+        let _ = writeln!(out, "geser_request_duration_seconds_count {}", cumulative);
+
+        write_metric(&mut out, "geser_handshake_failures_total", "counter", "TLS handshakes that failed or timed out.", &[(String::new(), get(&self.handshake_failures))]);
+        write_metric(&mut out, "geser_active_connections", "gauge", "Gemini connections currently open.", &[(String::new(), get(&self.active_connections))]);
+        write_metric(&mut out, "geser_cache_hits_total", "counter", "Page cache hits.", &[(String::new(), get(&self.cache_hits))]);
+        write_metric(&mut out, "geser_cache_misses_total", "counter", "Page cache misses.", &[(String::new(), get(&self.cache_misses))]);
+        write_metric(&mut out, "geser_cache_entries", "gauge", "Entries in the page cache.", &[(String::new(), cache.len() as u64)]);
+        write_metric(&mut out, "geser_cache_bytes", "gauge", "Size of the cached pages and files in bytes.", &[(String::new(), cache.size_bytes() as u64)]);
+        write_metric(&mut out, "geser_tls_reloads_total", "counter", "TLS configuration reloads by result.", &[
+            ("{result=\"success\"}".to_string(), get(&self.tls_reloads)),
+            ("{result=\"failure\"}".to_string(), get(&self.tls_reload_failures)),
+        ]);
+        write_metric(&mut out, "geser_config_reloads_total", "counter", "Configuration reloads by result.", &[
+            ("{result=\"success\"}".to_string(), get(&self.config_reloads)),
+            ("{result=\"failure\"}".to_string(), get(&self.config_reload_failures)),
+        ]);
+        out
+    }
+}
+
+// Writes one metric family: its help and type lines, then a sample per label set
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, u64)]) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+/// The global metrics instance.
+pub static METRICS: Metrics = Metrics::new();
+
+/// Increments a counter by one.
+pub fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Reads the current value of a counter.
+pub fn get(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+/// Counts a Gemini connection as active until dropped.
+pub struct ActiveConnection;
+
+impl ActiveConnection {
+    pub fn new() -> Self {
+        increment(&METRICS.active_connections);
+        ActiveConnection
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        METRICS.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Starts the metrics listener, serving the metrics at `/metrics` for Prometheus to scrape.
+pub async fn run_metrics_server(listener: TcpListener, cache: Cache) -> Result<()> {
+    let address = listener.local_addr()?;
+    tracing::info!("Metrics server started, listening on: http://{}/metrics", address);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let cache = cache.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_metrics_connection(stream, peer, cache).await {
+                tracing::error!("Error handling metrics connection {}: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+/// Handles a single scrape: `GET /metrics` returns the metrics, anything else 404.
+async fn handle_metrics_connection<S: AsyncRead + AsyncWrite + Unpin>(stream: S, peer: SocketAddr, cache: Cache) -> Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut buf_reader = AsyncBufReader::new(reader);
+    let mut request_line = String::new();
+    if buf_reader.read_line(&mut request_line).await? == 0 {
+        return Ok(());
+    }
+    // Skip the request headers; none of them change the response.
+    loop {
+        let mut header = String::new();
+        if buf_reader.read_line(&mut header).await? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+    tracing::debug!("Received metrics request from {}: {}", peer, request_line.trim_end());
+
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = METRICS.render(&cache);
+            write_response(&mut writer, "200 OK", "text/plain; version=0.0.4", body.as_bytes()).await
+        },
+        _ => write_response(&mut writer, "404 Not Found", "text/plain", b"Not Found").await,
+    }
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Test rendering recorded requests in the Prometheus text format
+    #[test]
+    fn test_render_metrics() {
+        let metrics = Metrics::new();
+        metrics.record_request(Some("20"), 100, Duration::from_millis(3));
+        metrics.record_request(Some("51"), 15, Duration::from_millis(30));
+        metrics.record_request(None, 0, Duration::from_secs(20));
+        let _active = ActiveConnection::new();
+
+        let cache = Cache::new();
+        cache.set_text("/index.md".to_string(), "hello".to_string());
+        let text = metrics.render(&cache);
+        assert!(text.contains("geser_requests_total{status=\"20\"} 1"));
+        assert!(text.contains("geser_requests_total{status=\"51\"} 1"));
+        assert!(text.contains("geser_requests_total{status=\"none\"} 1"));
+        assert!(text.contains("geser_response_bytes_total 115"));
+        assert!(text.contains("geser_request_duration_seconds_bucket{le=\"0.005\"} 1"));
+        assert!(text.contains("geser_request_duration_seconds_bucket{le=\"0.05\"} 2"));
+        assert!(text.contains("geser_request_duration_seconds_bucket{le=\"+Inf\"} 3"));
+        assert!(text.contains("geser_request_duration_seconds_count 3"));
+        assert!(text.contains("geser_cache_entries 1"));
+        assert!(text.contains("geser_cache_bytes 5"));
+        assert!(text.contains("# TYPE geser_active_connections gauge"));
+    }
+
+    // Test that the listener serves /metrics and nothing else
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        async fn request(raw: &[u8]) -> String {
+            let (mut client, server) = tokio::io::duplex(64 * 1024);
+            let task = tokio::spawn(handle_metrics_connection(server, "127.0.0.1:1".parse().unwrap(), Cache::new()));
+            client.write_all(raw).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            task.await.unwrap().unwrap();
+            response
+        }
+        let response = request(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("geser_tls_reloads_total{result=\"success\"}"));
+        assert!(request(b"GET / HTTP/1.1\r\n\r\n").await.starts_with("HTTP/1.1 404"));
+    }
+}
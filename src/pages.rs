@@ -1,28 +1,128 @@
-use anyhow::{Result, anyhow};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context};
 use tokio::fs;
 use pulldown_cmark::{Parser, Event, Tag, Options};
 use crate::cache::Cache;
+use crate::resolve::{resolve_file, SymlinkPolicy};
 
-/// Serves a Markdown file from the pages directory.
-/// If the safe_path is "/" then load "pages_dir/index.md", otherwise load "pages_dir{safe_path}.md".
-pub async fn serve_markdown(pages_dir: &str, safe_path: &str, cache: Cache) -> Result<String> {
-    let file_path = if safe_path == "/" {
-        format!("{}/index.md", pages_dir)
+/// Returns true if the sanitized path refers to a static asset (e.g., an image) rather than a Markdown page.
+pub fn is_static_asset(safe_path: &str) -> bool {
+    safe_path.ends_with(".jpg") || safe_path.ends_with(".jpeg") ||
+    safe_path.ends_with(".png") || safe_path.ends_with(".gif")
+}
+
+/// Maps a sanitized request path to the Markdown file backing it.
+/// If the safe_path ends with "/" (e.g. "/") this is "pages_dir{safe_path}index.md", otherwise "pages_dir{safe_path}.md".
+pub fn markdown_file_path(pages_dir: &str, safe_path: &str) -> String {
+    if safe_path.ends_with('/') {
+        format!("{}{}index.md", pages_dir, safe_path)
     } else {
         format!("{}{}.md", pages_dir, safe_path)
+    }
+}
+
+/// Maps a sanitized request path to the static file inside pages_dir.
+pub fn static_file_path(pages_dir: &str, safe_path: &str) -> String {
+    format!("{}{}", pages_dir, safe_path)
+}
+
+/// The file inside pages_dir that serves a sanitized request path, relative to pages_dir.
+pub fn page_file(safe_path: &str) -> String {
+    if is_static_asset(safe_path) { static_file_path("", safe_path) } else { markdown_file_path("", safe_path) }
+}
+
+/// A page resolved from pages_dir, independent of the protocol it is served over.
+pub enum Page {
+    /// Gemtext converted from a Markdown file.
+    Gemtext(String),
+    /// A static file and its MIME type.
+    Static(Vec<u8>, &'static str),
+}
+
+/// Looks up the page for a sanitized request path: static assets are served as-is, everything else
+/// is converted from the backing Markdown file. The file is first resolved against pages_dir,
+/// following symlinks only as `symlinks` allows, and exactly the resolved file is read.
+pub async fn lookup(pages_dir: &str, safe_path: &str, cache: Cache, symlinks: SymlinkPolicy) -> Result<Page> {
+    let file_path = match resolve_page(pages_dir, safe_path, symlinks).await? {
+        Ok(file_path) => file_path,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory | ErrorKind::InvalidInput | ErrorKind::PermissionDenied) => {
+            return Err(PageNotFound(format!("{}: {}", safe_path, e)).into());
+        },
+        Err(e) => return Err(anyhow::Error::new(e).context(format!("Cannot resolve {}", safe_path))),
     };
 
+    if is_static_asset(safe_path) {
+        let (data, mime) = serve_static_file(&file_path, cache).await?;
+        Ok(Page::Static(data, mime))
+    } else {
+        Ok(Page::Gemtext(serve_markdown(&file_path, cache).await?))
+    }
+}
+
+/// Resolves the file backing a sanitized request path against pages_dir under the symlink policy,
+/// returning its canonical path. Fails only if the blocking task cannot be run.
+pub async fn resolve_page(pages_dir: &str, safe_path: &str, symlinks: SymlinkPolicy) -> Result<std::io::Result<PathBuf>> {
+    let (root, relative) = (pages_dir.to_string(), page_file(safe_path));
+    Ok(tokio::task::spawn_blocking(move || resolve_file(Path::new(&root), &relative, symlinks)).await?)
+}
+
+/// A page that does not exist, or that may not be served under the symlink policy.
+#[derive(Debug)]
+pub struct PageNotFound(String);
+
+impl std::fmt::Display for PageNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Page not found: {}", self.0)
+    }
+}
+
+impl std::error::Error for PageNotFound {}
+
+/// Checks whether a failed lookup means that the page does not exist or may not be served,
+/// rather than that reading it failed on the server's side.
+pub fn is_not_found(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<PageNotFound>().is_some()
+}
+
+/// Checks whether a sanitized request path names a directory inside pages_dir, resolved like pages are.
+pub async fn is_directory(pages_dir: &str, safe_path: &str, symlinks: SymlinkPolicy) -> bool {
+    let root = pages_dir.to_string();
+    let relative = safe_path.to_string();
+    tokio::task::spawn_blocking(move || resolve_file(Path::new(&root), &relative, symlinks)).await
+        .is_ok_and(|resolved| resolved.is_ok_and(|path| path.is_dir()))
+}
+
+/// Serves a Markdown file, given by its resolved path, converted to Gemtext.
+pub async fn serve_markdown(file_path: &Path, cache: Cache) -> Result<String> {
+    let key = file_path.to_string_lossy().to_string();
+
     // Check cache first.
-    if let Some(content) = cache.get_text(&file_path) {
+    if let Some(content) = cache.get_text(&key) {
         return Ok(content);
     }
 
-    let content = fs::read_to_string(&file_path).await
-        .map_err(|e| anyhow!("Failed to read file {}: {:?}", file_path, e))?;
-    
+    let content = fs::read_to_string(file_path).await
+        .with_context(|| format!("Failed to read file {}", file_path.display()))?;
+    let output = markdown_to_gemtext(&content);
+
+    // Cache the converted content.
+    cache.set_text(key, output.clone());
+    Ok(output)
+}
+
+/// Converts Markdown content into Gemini formatted text.
+pub fn markdown_to_gemtext(content: &str) -> String {
+    markdown_to_gemtext_with_warnings(content).0
+}
+
+/// Converts Markdown content into Gemini formatted text, also returning a warning for each
+/// Markdown construct the conversion cannot represent and drops or flattens.
+pub fn markdown_to_gemtext_with_warnings(content: &str) -> (String, Vec<String>) {
     // Use pulldown-cmark to parse Markdown content.
-    let parser = Parser::new_ext(&content, Options::all());
+    let parser = Parser::new_ext(content, Options::all());
     let mut output = String::new();
+    let mut warnings = Vec::new();
 
     // State variables for handling links.
     let mut in_link = false;
@@ -87,32 +187,71 @@ pub async fn serve_markdown(pages_dir: &str, safe_path: &str, cache: Cache) -> R
             Event::SoftBreak | Event::HardBreak => {
                 output.push('\n');
             },
+            // Constructs without a gemtext equivalent are reported.
+            Event::Html(html) => {
+                warnings.push(format!("raw HTML is dropped: {}", html.trim()));
+            },
+            Event::Start(Tag::Table(_)) => {
+                warnings.push("tables are flattened into plain text".to_string());
+            },
+            Event::FootnoteReference(name) => {
+                warnings.push(format!("footnote [^{}] is not supported", name));
+            },
             // Other events are ignored for simplicity.
             _ => {}
         }
     }
-    // Cache the converted content.
-    cache.set_text(file_path, output.clone());
-    Ok(output)
+    (output, warnings)
 }
 
-/// Serves a static file (e.g., an image) from the pages directory.
-/// The safe_path corresponds to a file inside pages_dir.
-pub async fn serve_static_file(pages_dir: &str, safe_path: &str, cache: Cache) -> Result<(Vec<u8>, &'static str)> {
-    let file_path = format!("{}{}", pages_dir, safe_path);
+/// Rewrites Spartan `=:` prompt lines into ordinary `=>` link lines, for clients that do not understand them.
+/// Gemini clients following such a link are expected to be asked for input by the target page.
+pub fn rewrite_prompt_links(gemtext: &str) -> String {
+    gemtext
+        .split_inclusive('\n')
+        .map(|line| match line.strip_prefix("=:") {
+            Some(rest) => format!("=>{}", rest),
+            None => line.to_string(),
+        })
+        .collect()
+}
+
+/// Placeholder in pages that is replaced by the registered username of the client.
+pub const USERNAME_PLACEHOLDER: &str = "{{username}}";
+
+/// Fills in the username placeholder, with `guest` for clients that are not registered
+/// or protocols without client certificates.
+pub fn fill_username(gemtext: &str, username: Option<&str>) -> String {
+    gemtext.replace(USERNAME_PLACEHOLDER, username.unwrap_or("guest"))
+}
+
+/// Placeholder in pages that is replaced by the input sent to them: the query of a Gemini or
+/// HTTP request, the data of a Spartan upload or a Gopher search string.
+pub const INPUT_PLACEHOLDER: &str = "{{input}}";
+
+/// Fills in the input placeholder, with nothing if no input was sent. Line breaks and other
+/// control characters become spaces, so input cannot add lines such as links to the page.
+pub fn fill_input(gemtext: &str, input: Option<&str>) -> String {
+    let input: String = input.unwrap_or_default().chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+    gemtext.replace(INPUT_PLACEHOLDER, &input)
+}
+
+/// Serves a static file (e.g., an image), given by its resolved path, with the MIME type for its extension.
+pub async fn serve_static_file(file_path: &Path, cache: Cache) -> Result<(Vec<u8>, &'static str)> {
+    let key = file_path.to_string_lossy().to_string();
+    let mime = get_mime_type(&key);
     // Check cache for binary file.
-    if let Some(data) = cache.get_binary(&file_path) {
-        let mime = get_mime_type(safe_path);
+    if let Some(data) = cache.get_binary(&key) {
         return Ok((data, mime));
     }
-    let data = fs::read(&file_path).await
-        .map_err(|e| anyhow!("Failed to read file {}: {:?}", file_path, e))?;
-    let mime = get_mime_type(safe_path);
-    cache.set_binary(file_path, data.clone());
+    let data = fs::read(file_path).await
+        .with_context(|| format!("Failed to read file {}", file_path.display()))?;
+    cache.set_binary(key, data.clone());
     Ok((data, mime))
 }
 
-fn get_mime_type(path: &str) -> &'static str {
+/// Returns the MIME type of a static file, based on its extension.
+pub fn get_mime_type(path: &str) -> &'static str {
     if path.ends_with(".jpg") || path.ends_with(".jpeg") {
         "image/jpeg"
     } else if path.ends_with(".png") {
@@ -129,7 +268,6 @@ fn get_mime_type(path: &str) -> &'static str {
 mod tests {
     use super::*;  // Import outer module contents
     use crate::cache::Cache;
-    use std::env;
     use tokio::fs;
     
     // Test serving Markdown files
@@ -145,7 +283,7 @@ mod tests {
         fs::write(&file_path, content).await.unwrap();
 
         // Test serving the Markdown file
-        let result = serve_markdown(pages_dir, safe_path, cache).await;
+        let result = serve_markdown(Path::new(&markdown_file_path(pages_dir, safe_path)), cache).await;
         assert!(result.is_ok(), "The Markdown file should be served correctly");
         let result_content = result.unwrap();
         assert!(result_content.contains("Hello World"));
@@ -165,13 +303,84 @@ mod tests {
         fs::write(&file_path, &data).await.unwrap();
 
         // Test serving the static file
-        let result = serve_static_file(pages_dir, safe_path, cache).await;
+        let result = serve_static_file(Path::new(&file_path), cache).await;
         assert!(result.is_ok(), "The static file should be served correctly");
         let (served_data, mime_type) = result.unwrap();
         assert_eq!(mime_type, "image/jpeg");
         assert_eq!(served_data, data);
     }
 
+    // Test that lookups read and cache exactly the resolved file
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_lookup_resolved_file() {
+        let base = std::env::temp_dir().join(format!("geser-lookup-{}", std::process::id()));
+        let pages_dir = base.join("pages");
+        std::fs::create_dir_all(pages_dir.join("docs")).unwrap();
+        std::fs::write(pages_dir.join("docs/page.md"), "# Docs").unwrap();
+        std::fs::write(base.join("secret.md"), "# Secret").unwrap();
+        std::os::unix::fs::symlink(pages_dir.join("docs"), pages_dir.join("alias")).unwrap();
+        std::os::unix::fs::symlink(base.join("secret.md"), pages_dir.join("escape.md")).unwrap();
+        let pages_dir_str = pages_dir.to_string_lossy().to_string();
+        let cache = Cache::new();
+
+        let page = lookup(&pages_dir_str, "/alias/page", cache.clone(), SymlinkPolicy::Inside).await.unwrap();
+        assert!(matches!(page, Page::Gemtext(content) if content.contains("Docs")));
+        let resolved = pages_dir.canonicalize().unwrap().join("docs/page.md");
+        assert!(cache.get_text(&resolved.to_string_lossy()).is_some(), "The page is cached under the file it was read from");
+        assert_eq!(cache.len(), 1);
+
+        let escaped = lookup(&pages_dir_str, "/escape", cache.clone(), SymlinkPolicy::Inside).await;
+        assert!(escaped.is_err_and(|e| is_not_found(&e)));
+        assert_eq!(cache.len(), 1);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    // Test mapping request paths to files
+    #[test]
+    fn test_page_file_paths() {
+        assert_eq!(markdown_file_path("pages", "/"), "pages/index.md");
+        assert_eq!(markdown_file_path("pages", "/about"), "pages/about.md");
+        assert_eq!(markdown_file_path("pages", "/blog/"), "pages/blog/index.md");
+        assert_eq!(static_file_path("pages", "/images/a.png"), "pages/images/a.png");
+        assert!(is_static_asset("/images/a.png"));
+        assert!(!is_static_asset("/about"));
+    }
+
+    // Test that unsupported Markdown is reported
+    #[test]
+    fn test_conversion_warnings() {
+        let (output, warnings) = markdown_to_gemtext_with_warnings("# Title\n\n<div>hi</div>\n");
+        assert!(output.contains("# Title"));
+        assert_eq!(warnings, vec!["raw HTML is dropped: <div>hi</div>".to_string()]);
+        assert!(markdown_to_gemtext_with_warnings("plain text").1.is_empty());
+    }
+
+    // Test rewriting of Spartan prompt lines
+    #[test]
+    fn test_rewrite_prompt_links() {
+        let gemtext = "# Guestbook\n=: /sign Sign the guestbook\n=> / Home\ntext =: kept\n";
+        assert_eq!(
+            rewrite_prompt_links(gemtext),
+            "# Guestbook\n=> /sign Sign the guestbook\n=> / Home\ntext =: kept\n"
+        );
+    }
+
+    // Test filling in the username placeholder
+    #[test]
+    fn test_fill_username() {
+        assert_eq!(fill_username("Hello, {{username}}!", Some("alice")), "Hello, alice!");
+        assert_eq!(fill_username("Hello, {{username}}!", None), "Hello, guest!");
+    }
+
+    // Test filling in the input placeholder
+    #[test]
+    fn test_fill_input() {
+        assert_eq!(fill_input("You said: {{input}}", Some("hello")), "You said: hello");
+        assert_eq!(fill_input("You said: {{input}}", None), "You said: ");
+        assert_eq!(fill_input("{{input}}", Some("a\n=> /evil link")), "a => /evil link", "Input cannot start new lines");
+    }
+
     // Test mime type detection
     #[test]
     fn test_get_mime_type() {
@@ -0,0 +1,113 @@
+use anyhow::{Result, anyhow};
+
+use crate::config::Settings;
+
+/// Whether `drop_privileges` will chroot or switch user or group, which it only does when started
+/// as root. Files opened by path before then may be out of reach afterwards.
+#[cfg(unix)]
+pub fn drops_privileges(settings: &Settings) -> bool {
+    (settings.user.is_some() || settings.group.is_some() || settings.chroot) && nix::unistd::geteuid().is_root()
+}
+
+#[cfg(not(unix))]
+pub fn drops_privileges(_settings: &Settings) -> bool {
+    false
+}
+
+/// Drops root privileges as configured, once the listeners are bound and the TLS material is loaded:
+/// chroots into `pages_dir` if `chroot` is set, then switches to `group` and `user`.
+/// After a chroot `pages_dir` is updated to `/`, and once privileges are dropped
+/// `privileges_dropped` is set. Fails if any step fails, so the server never keeps running with
+/// more privileges than configured.
+#[cfg(unix)]
+pub fn drop_privileges(settings: &mut Settings) -> Result<()> {
+    use nix::unistd::{self, Uid};
+
+    if settings.user.is_none() && settings.group.is_none() && !settings.chroot {
+        return Ok(());
+    }
+    // Users and groups are looked up before the chroot hides /etc/passwd and /etc/group
+    let (uid, gid) = resolve_ids(settings.user.as_deref(), settings.group.as_deref())?;
+    if !unistd::geteuid().is_root() {
+        if settings.chroot || uid.is_some_and(|uid| uid != unistd::getuid()) || gid.is_some_and(|gid| gid != unistd::getgid()) {
+            return Err(anyhow!("Dropping privileges requires starting as root"));
+        }
+        return Ok(());
+    }
+
+    if settings.chroot {
+        let root = std::fs::canonicalize(&settings.pages_dir)
+            .map_err(|e| anyhow!("Cannot chroot into pages_dir {}: {}", settings.pages_dir, e))?;
+        unistd::chroot(&root).map_err(|e| anyhow!("Failed to chroot into {}: {}", root.display(), e))?;
+        unistd::chdir("/").map_err(|e| anyhow!("Failed to change directory after chroot: {}", e))?;
+        tracing::info!("Changed root to {}", root.display());
+        settings.pages_dir = "/".to_string();
+    }
+    if let Some(gid) = gid {
+        // Supplementary groups are reset too, so none of root's groups are kept
+        unistd::setgroups(&[gid]).map_err(|e| anyhow!("Failed to set supplementary groups: {}", e))?;
+        unistd::setgid(gid).map_err(|e| anyhow!("Failed to switch to group {}: {}", gid, e))?;
+    }
+    if let Some(uid) = uid {
+        unistd::setuid(uid).map_err(|e| anyhow!("Failed to switch to user {}: {}", uid, e))?;
+        // Make sure root cannot be regained
+        if !uid.is_root() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+            return Err(anyhow!("Still able to regain root after switching to user {}", uid));
+        }
+    }
+    tracing::info!("Running as user {} and group {}", unistd::geteuid(), unistd::getegid());
+    settings.privileges_dropped = true;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(settings: &mut Settings) -> Result<()> {
+    if settings.user.is_some() || settings.group.is_some() || settings.chroot {
+        return Err(anyhow!("Dropping privileges is only supported on Unix"));
+    }
+    Ok(())
+}
+
+// Looks up the user and group to switch to. Without a group, the user's primary group is used.
+#[cfg(unix)]
+fn resolve_ids(user: Option<&str>, group: Option<&str>) -> Result<(Option<nix::unistd::Uid>, Option<nix::unistd::Gid>)> {
+    use nix::unistd::{Group, User};
+
+    let user = match user {
+        Some(name) => Some(User::from_name(name)?.ok_or_else(|| anyhow!("Unknown user {}", name))?),
+        None => None,
+    };
+    let gid = match group {
+        Some(name) => Some(Group::from_name(name)?.ok_or_else(|| anyhow!("Unknown group {}", name))?.gid),
+        None => user.as_ref().map(|user| user.gid),
+    };
+    Ok((user.map(|user| user.uid), gid))
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+
+    // Test looking up users and groups
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_ids() {
+        let (uid, gid) = resolve_ids(Some("root"), None).unwrap();
+        assert_eq!(uid.map(|uid| uid.as_raw()), Some(0));
+        assert_eq!(gid.map(|gid| gid.as_raw()), Some(0), "The user's primary group is used by default");
+        assert_eq!(resolve_ids(None, None).unwrap(), (None, None));
+        assert!(resolve_ids(Some("geser-no-such-user"), None).is_err());
+        assert!(resolve_ids(None, Some("geser-no-such-group")).is_err());
+    }
+
+    // Test that nothing happens unless a drop is configured
+    #[test]
+    fn test_drop_not_configured() {
+        let mut settings = Settings { pages_dir: "pages".to_string(), ..Settings::default() };
+        assert!(!drops_privileges(&settings));
+        assert!(drop_privileges(&mut settings).is_ok());
+        assert_eq!(settings.pages_dir, "pages");
+        assert!(!settings.privileges_dropped);
+    }
+}
@@ -0,0 +1,200 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use anyhow::{Result, anyhow};
+use ipnet::IpNet;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Signature starting a PROXY protocol v2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// PROXY v2 TLV type carrying the host name the client asked for (its SNI).
+pub const PP2_TYPE_AUTHORITY: u8 = 0x02;
+
+/// Longest possible PROXY protocol v1 header, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// A PROXY protocol header sent by a load balancer ahead of the client's data.
+#[derive(Debug, Default, PartialEq)]
+pub struct ProxyHeader {
+    /// The client's address, or `None` for health checks (`LOCAL`) and unknown protocols.
+    pub source: Option<SocketAddr>,
+    /// Type-length-value records from a v2 header.
+    pub tlvs: Vec<(u8, Vec<u8>)>,
+}
+
+impl ProxyHeader {
+    /// The value of the first TLV of the given type.
+    pub fn tlv(&self, kind: u8) -> Option<&[u8]> {
+        self.tlvs.iter().find(|(tlv_kind, _)| *tlv_kind == kind).map(|(_, value)| value.as_slice())
+    }
+}
+
+/// Reads a client certificate's SHA-256 fingerprint from a TLV, sent either as the 32 raw bytes
+/// of the hash or as 64 hex digits. Returns it as lowercase hex, like `cert_fingerprint`.
+pub fn fingerprint_from_tlv(value: &[u8]) -> Option<String> {
+    match value.len() {
+        32 => Some(value.iter().map(|b| format!("{:02x}", b)).collect()),
+        64 if value.iter().all(u8::is_ascii_hexdigit) => Some(String::from_utf8_lossy(value).to_ascii_lowercase()),
+        _ => None,
+    }
+}
+
+/// Checks whether PROXY headers are accepted from an address.
+pub fn is_trusted(trusted: &[IpNet], ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    };
+    trusted.iter().any(|net| net.contains(&ip))
+}
+
+/// Reads a v1 or v2 PROXY protocol header, consuming exactly the header's bytes.
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> Result<ProxyHeader> {
+    // Every header is at least 12 bytes long: a v2 signature, or the start of a v1 line
+    let mut start = [0u8; 12];
+    reader.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        reader.read_exact(&mut fixed).await?;
+        let length = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        let mut body = vec![0u8; length];
+        reader.read_exact(&mut body).await?;
+        return parse_v2(fixed[0], fixed[1], &body);
+    }
+    if start.starts_with(b"PROXY ") {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(anyhow!("PROXY v1 header is too long"));
+            }
+            line.push(reader.read_u8().await?);
+        }
+        let line = std::str::from_utf8(&line).map_err(|_| anyhow!("PROXY v1 header is not valid text"))?;
+        return Ok(ProxyHeader { source: parse_v1(line.trim_end())?, tlvs: Vec::new() });
+    }
+    Err(anyhow!("Connection did not start with a PROXY protocol header"))
+}
+
+// Parses a v1 line such as `PROXY TCP4 192.0.2.1 198.51.100.1 56324 1965`
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            let ip = source.parse::<IpAddr>().map_err(|_| anyhow!("Invalid PROXY v1 source address {}", source))?;
+            let port = source_port.parse::<u16>().map_err(|_| anyhow!("Invalid PROXY v1 source port {}", source_port))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        },
+        _ => Err(anyhow!("Malformed PROXY v1 header: {}", line)),
+    }
+}
+
+// Parses the address block and TLVs of a v2 header
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> Result<ProxyHeader> {
+    if version_command >> 4 != 2 {
+        return Err(anyhow!("Unsupported PROXY protocol version {}", version_command >> 4));
+    }
+    let (source, addresses_length) = match (version_command & 0x0f, family >> 4) {
+        // LOCAL: sent by the balancer itself, e.g. for health checks
+        (0x0, _) => (None, address_block_length(family >> 4)),
+        (0x1, 0x1) if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            (Some(SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([body[8], body[9]]))), 12)
+        },
+        (0x1, 0x2) if body.len() >= 36 => {
+            let octets: [u8; 16] = body[..16].try_into()?;
+            (Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), u16::from_be_bytes([body[32], body[33]]))), 36)
+        },
+        (0x1, 0x0 | 0x3) => (None, address_block_length(family >> 4)),
+        (0x1, _) => return Err(anyhow!("PROXY v2 address block is too short")),
+        (command, _) => return Err(anyhow!("Unknown PROXY v2 command {}", command)),
+    };
+
+    let mut tlvs = Vec::new();
+    let mut rest = body.get(addresses_length.min(body.len())..).unwrap_or_default();
+    while rest.len() >= 3 {
+        let length = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+        let value = rest.get(3..3 + length).ok_or_else(|| anyhow!("Truncated PROXY v2 TLV"))?;
+        tlvs.push((rest[0], value.to_vec()));
+        rest = &rest[3 + length..];
+    }
+    Ok(ProxyHeader { source, tlvs })
+}
+
+// Size of the address block for an address family: IPv4, IPv6, Unix or unspecified
+fn address_block_length(family: u8) -> usize {
+    match family {
+        0x1 => 12,
+        0x2 => 36,
+        0x3 => 216,
+        _ => 0,
+    }
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+
+    // Test parsing v1 headers, leaving the client's data unread
+    #[tokio::test]
+    async fn test_read_v1_header() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 1965\r\n\x16\x03\x01";
+        let header = read_header(&mut stream).await.unwrap();
+        assert_eq!(header.source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(stream, b"\x16\x03\x01", "Only the header is consumed");
+
+        let mut stream: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 1965\r\n";
+        assert_eq!(read_header(&mut stream).await.unwrap().source, Some("[2001:db8::1]:4000".parse().unwrap()));
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut stream).await.unwrap().source, None);
+        let mut stream: &[u8] = b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03\x00";
+        assert!(read_header(&mut stream).await.is_err(), "A TLS hello is not a PROXY header");
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(200));
+        assert!(read_header(&mut long.as_bytes()).await.is_err());
+    }
+
+    // Test parsing v2 headers with TLVs
+    #[tokio::test]
+    async fn test_read_v2_header() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0x00, 12 + 6]);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1]);
+        header.extend_from_slice(&56324u16.to_be_bytes());
+        header.extend_from_slice(&1965u16.to_be_bytes());
+        header.extend_from_slice(&[0x02, 0x00, 0x03]);
+        header.extend_from_slice(b"a.b");
+        header.extend_from_slice(b"rest");
+
+        let mut stream: &[u8] = &header;
+        let parsed = read_header(&mut stream).await.unwrap();
+        assert_eq!(parsed.source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(parsed.tlvs, vec![(0x02, b"a.b".to_vec())]);
+        assert_eq!(parsed.tlv(PP2_TYPE_AUTHORITY), Some(&b"a.b"[..]));
+        assert_eq!(stream, b"rest");
+
+        // LOCAL connections carry no client address
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(read_header(&mut local.as_slice()).await.unwrap(), ProxyHeader::default());
+    }
+
+    // Test reading certificate fingerprints in both encodings
+    #[test]
+    fn test_fingerprint_from_tlv() {
+        let hex = "ab".repeat(32);
+        assert_eq!(fingerprint_from_tlv(&[0xab; 32]), Some(hex.clone()));
+        assert_eq!(fingerprint_from_tlv(hex.to_uppercase().as_bytes()), Some(hex));
+        assert_eq!(fingerprint_from_tlv(b"short"), None);
+    }
+
+    // Test matching trusted ranges
+    #[test]
+    fn test_is_trusted() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap(), "2001:db8::/32".parse().unwrap()];
+        assert!(is_trusted(&trusted, "10.1.2.3".parse().unwrap()));
+        assert!(is_trusted(&trusted, "::ffff:10.1.2.3".parse().unwrap()));
+        assert!(is_trusted(&trusted, "2001:db8::1".parse().unwrap()));
+        assert!(!is_trusted(&trusted, "192.0.2.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,226 @@
+use dashmap::DashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimitSettings;
+
+/// Outcome of checking a request against the rate limit.
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Allow,
+    /// Over the limit; the client should retry after this many seconds (sent as `44 <seconds>`).
+    SlowDown(u64),
+    /// Temporarily banned for ignoring slow-down responses; the connection is closed.
+    Banned,
+}
+
+/// Token bucket for one client (an IPv4 address or an IPv6 /64).
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // Requests rejected since the client last got through
+    rejected: u32,
+    banned_until: Option<Instant>,
+}
+
+/// Per-client token-bucket rate limiter with a temporary ban list.
+#[derive(Clone)]
+pub struct RateLimiter {
+    buckets: Arc<DashMap<IpAddr, Bucket>>,
+}
+
+/// Groups addresses by client: IPv6 clients usually get a whole /64, so it counts as one client.
+pub fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let segments = v6.segments();
+                IpAddr::V6(Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3], 0, 0, 0, 0))
+            }
+        },
+    }
+}
+
+impl RateLimiter {
+    // Creates a new RateLimiter instance
+    pub fn new() -> Self {
+        RateLimiter {
+            buckets: Arc::new(DashMap::new()),
+        }
+    }
+
+    // Checks whether a client is currently banned, so its connection can be dropped before the handshake
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.buckets.get(&client_key(ip))
+            .and_then(|bucket| bucket.banned_until)
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    // Takes a token for a request from the client
+    pub fn check(&self, ip: IpAddr, settings: &RateLimitSettings) -> Decision {
+        self.check_at(ip, settings, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, settings: &RateLimitSettings, now: Instant) -> Decision {
+        if !settings.enabled {
+            return Decision::Allow;
+        }
+        let rate = settings.requests_per_minute as f64 / 60.0;
+        let capacity = settings.burst as f64;
+        let mut bucket = self.buckets.entry(client_key(ip)).or_insert_with(|| Bucket {
+            tokens: capacity,
+            updated: now,
+            rejected: 0,
+            banned_until: None,
+        });
+
+        if let Some(until) = bucket.banned_until {
+            if until > now {
+                return Decision::Banned;
+            }
+            bucket.banned_until = None;
+            bucket.rejected = 0;
+        }
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.rejected = 0;
+            return Decision::Allow;
+        }
+        bucket.rejected += 1;
+        if settings.ban_after > 0 && bucket.rejected > settings.ban_after {
+            bucket.banned_until = Some(now + Duration::from_secs(settings.ban_secs));
+            tracing::warn!("Banning {} for {} seconds for ignoring slow down responses", client_key(ip), settings.ban_secs);
+            return Decision::Banned;
+        }
+        Decision::SlowDown(((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64)
+    }
+
+    // Forgets clients whose buckets have refilled and who are not banned, to bound memory use
+    pub fn prune(&self, settings: &RateLimitSettings) {
+        let now = Instant::now();
+        let refill = Duration::from_secs_f64(settings.burst as f64 * 60.0 / settings.requests_per_minute.max(1) as f64);
+        self.buckets.retain(|_, bucket| {
+            bucket.banned_until.is_some_and(|until| until > now) || now.saturating_duration_since(bucket.updated) < refill
+        });
+    }
+}
+
+/// Counts open connections per client (an IPv4 address or an IPv6 /64), to cap how many one client may hold.
+#[derive(Clone)]
+pub struct ConnectionCounter {
+    counts: Arc<DashMap<IpAddr, usize>>,
+}
+
+/// Holds one of a client's connection slots until dropped.
+pub struct ConnectionGuard {
+    counts: Arc<DashMap<IpAddr, usize>>,
+    key: IpAddr,
+}
+
+impl ConnectionCounter {
+    // Creates a new ConnectionCounter instance
+    pub fn new() -> Self {
+        ConnectionCounter {
+            counts: Arc::new(DashMap::new()),
+        }
+    }
+
+    // Takes a connection slot for the client, unless it already has `max` open connections
+    pub fn try_acquire(&self, ip: IpAddr, max: usize) -> Option<ConnectionGuard> {
+        let key = client_key(ip);
+        let mut count = self.counts.entry(key).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionGuard { counts: self.counts.clone(), key })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        // Forget the client once its last connection is closed
+        self.counts.remove_if_mut(&self.key, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+
+    fn settings() -> RateLimitSettings {
+        RateLimitSettings {
+            enabled: true,
+            requests_per_minute: 60,
+            burst: 2,
+            ban_after: 2,
+            ban_secs: 60,
+        }
+    }
+
+    // Test that IPv6 clients are grouped by /64
+    #[test]
+    fn test_client_key() {
+        let a: IpAddr = "2001:db8:1:2:aaaa::1".parse().unwrap();
+        let b: IpAddr = "2001:db8:1:2:bbbb::2".parse().unwrap();
+        let c: IpAddr = "2001:db8:1:3::1".parse().unwrap();
+        assert_eq!(client_key(a), client_key(b));
+        assert_ne!(client_key(a), client_key(c));
+        assert_eq!(client_key("::ffff:192.0.2.1".parse().unwrap()), "192.0.2.1".parse::<IpAddr>().unwrap());
+        assert_eq!(client_key("192.0.2.1".parse().unwrap()), "192.0.2.1".parse::<IpAddr>().unwrap());
+    }
+
+    // Test the per-client connection cap
+    #[test]
+    fn test_connection_counter() {
+        let counter = ConnectionCounter::new();
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        let first = counter.try_acquire(ip, 2).unwrap();
+        let _second = counter.try_acquire("2001:db8::2".parse().unwrap(), 2).unwrap();
+        assert!(counter.try_acquire(ip, 2).is_none(), "The /64 already has two connections");
+        assert!(counter.try_acquire("192.0.2.1".parse().unwrap(), 2).is_some());
+        drop(first);
+        assert!(counter.try_acquire(ip, 2).is_some(), "Closing a connection frees a slot");
+        drop(_second);
+        assert!(counter.counts.is_empty(), "Clients without connections are forgotten");
+    }
+
+    // Test the token bucket, slow down responses and the ban for ignoring them
+    #[test]
+    fn test_rate_limit() {
+        let limiter = RateLimiter::new();
+        let settings = settings();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+
+        assert_eq!(limiter.check_at(ip, &settings, start), Decision::Allow);
+        assert_eq!(limiter.check_at(ip, &settings, start), Decision::Allow);
+        assert_eq!(limiter.check_at(ip, &settings, start), Decision::SlowDown(1));
+        assert_eq!(limiter.check_at("192.0.2.2".parse().unwrap(), &settings, start), Decision::Allow, "Other clients are not limited");
+
+        // One token is refilled per second
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limiter.check_at(ip, &settings, later), Decision::Allow);
+        assert_eq!(limiter.check_at(ip, &settings, later), Decision::SlowDown(1));
+        assert_eq!(limiter.check_at(ip, &settings, later), Decision::SlowDown(1));
+        assert_eq!(limiter.check_at(ip, &settings, later), Decision::Banned, "Ignoring 44 twice leads to a ban");
+        assert_eq!(limiter.check_at(ip, &settings, later + Duration::from_secs(30)), Decision::Banned);
+        assert!(limiter.is_banned(ip));
+        assert_eq!(limiter.check_at(ip, &settings, later + Duration::from_secs(61)), Decision::Allow, "Bans expire");
+
+        let disabled = RateLimitSettings { enabled: false, ..settings };
+        assert_eq!(limiter.check_at("192.0.2.3".parse().unwrap(), &disabled, start), Decision::Allow);
+        assert_eq!(limiter.buckets.len(), 2);
+    }
+}
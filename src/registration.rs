@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use anyhow::{Result, Context, anyhow};
+use percent_encoding::percent_decode_str;
+
+use crate::config::RegistrationSettings;
+
+/// Checks that a username can be stored and logged: non-empty, printable and without spaces.
+pub fn is_valid_username(username: &str) -> bool {
+    !username.is_empty() && username.chars().all(|c| !c.is_whitespace() && !c.is_control())
+}
+
+/// The registered certificates and the store file new registrations are appended to.
+struct Store {
+    /// Usernames by lowercase certificate fingerprint.
+    users: HashMap<String, String>,
+    file: Option<File>,
+}
+
+/// Client certificates registered with an invite code, kept in a file of `fingerprint username` lines.
+#[derive(Clone)]
+pub struct Registrations {
+    store: Arc<Mutex<Store>>,
+}
+
+impl Registrations {
+    // Loads the registered certificates and opens the store for appending. The file is opened
+    // up front, so registrations still work after dropping privileges or a chroot.
+    pub fn open(settings: &RegistrationSettings) -> Result<Self> {
+        let mut users = HashMap::new();
+        let mut file = None;
+        if settings.enabled {
+            match std::fs::read_to_string(&settings.store) {
+                Ok(content) => {
+                    for (number, line) in content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+                        let (fingerprint, username) = line.split_once(' ')
+                            .ok_or_else(|| anyhow!("Malformed line {} in registration store {}", number + 1, settings.store))?;
+                        users.insert(fingerprint.to_ascii_lowercase(), username.trim().to_string());
+                    }
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => return Err(anyhow!("Failed to read registration store {}: {}", settings.store, e)),
+            }
+            file = Some(OpenOptions::new().create(true).append(true).open(&settings.store)
+                .with_context(|| format!("Failed to open registration store {}", settings.store))?);
+            tracing::info!("Loaded {} registered certificate(s) from {}", users.len(), settings.store);
+        }
+        Ok(Registrations { store: Arc::new(Mutex::new(Store { users, file })) })
+    }
+
+    /// The username a certificate was registered under.
+    pub fn username(&self, fingerprint: Option<&str>) -> Option<String> {
+        let fingerprint = fingerprint?.to_ascii_lowercase();
+        self.lock().users.get(&fingerprint).cloned()
+    }
+
+    // Registers a certificate under a username, unless the username is already taken.
+    // The store file is written and synced on a blocking thread, off the runtime.
+    async fn register(&self, fingerprint: &str, username: &str) -> Result<bool> {
+        let store = self.store.clone();
+        let fingerprint = fingerprint.to_ascii_lowercase();
+        let username = username.to_string();
+        tokio::task::spawn_blocking(move || {
+            let mut store = lock(&store);
+            if store.users.values().any(|taken| *taken == username) {
+                return Ok(false);
+            }
+            let file = store.file.as_mut().ok_or_else(|| anyhow!("Registration is not enabled"))?;
+            file.write_all(format!("{} {}\n", fingerprint, username).as_bytes())?;
+            file.sync_data()?;
+            store.users.insert(fingerprint, username);
+            Ok(true)
+        }).await?
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Store> {
+        lock(&self.store)
+    }
+}
+
+// Locks the store, recovering it if a request panicked while holding the lock
+fn lock(store: &Mutex<Store>) -> std::sync::MutexGuard<'_, Store> {
+    store.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Checks whether a sanitized request path is the registration page.
+pub fn is_registration_path(settings: &RegistrationSettings, path: &str) -> bool {
+    settings.enabled && path.trim_end_matches('/') == settings.path.trim_end_matches('/')
+}
+
+/// Handles a request for the registration page and returns the complete response.
+/// Clients without a certificate get `60`; others are asked for an invite code with `11`, as it
+/// is a secret, and a valid, unused code registers their certificate under the invite's username.
+pub async fn handle_request(query: Option<&str>, fingerprint: Option<&str>, settings: &RegistrationSettings, registrations: &Registrations) -> String {
+    let Some(fingerprint) = fingerprint else {
+        return "60 Client certificate required to register\r\n".to_string();
+    };
+    if let Some(username) = registrations.username(Some(fingerprint)) {
+        return format!("20 text/gemini\r\n# Registered\n\nThis certificate is registered as {}.\n", username);
+    }
+    let Some(query) = query.filter(|query| !query.is_empty()) else {
+        return "11 Invite code\r\n".to_string();
+    };
+    let code = percent_decode_str(query).decode_utf8_lossy();
+    let Some(invite) = settings.invites.iter().find(|invite| invite.code == code.trim()) else {
+        tracing::warn!("Refused registration of certificate {} with an unknown invite code", fingerprint);
+        return "11 Unknown invite code, please try again\r\n".to_string();
+    };
+    match registrations.register(fingerprint, &invite.username).await {
+        Ok(true) => {
+            tracing::info!("Registered certificate {} as {}", fingerprint, invite.username);
+            format!("20 text/gemini\r\n# Welcome, {}\n\nThis certificate is now registered.\n", invite.username)
+        },
+        Ok(false) => {
+            tracing::warn!("Refused registration of certificate {}: the invite for {} was already used", fingerprint, invite.username);
+            "11 This invite code has already been used\r\n".to_string()
+        },
+        Err(e) => {
+            tracing::error!("Failed to register certificate {}: {:?}", fingerprint, e);
+            "40 Registration failed\r\n".to_string()
+        },
+    }
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+    use crate::config::Invite;
+
+    // Test the registration flow and that registrations survive a restart
+    #[tokio::test]
+    async fn test_register() {
+        let store = std::env::temp_dir().join(format!("geser-registrations-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&store);
+        let settings = RegistrationSettings {
+            enabled: true,
+            store: store.to_string_lossy().to_string(),
+            invites: vec![Invite { code: "open sesame".to_string(), username: "alice".to_string() }],
+            ..RegistrationSettings::default()
+        };
+        let registrations = Registrations::open(&settings).unwrap();
+
+        assert!(handle_request(None, None, &settings, &registrations).await.starts_with("60 "));
+        assert_eq!(handle_request(None, Some("AB"), &settings, &registrations).await, "11 Invite code\r\n");
+        assert!(handle_request(Some("wrong"), Some("AB"), &settings, &registrations).await.starts_with("11 Unknown"));
+        assert!(handle_request(Some("open%20sesame"), Some("AB"), &settings, &registrations).await.contains("Welcome, alice"));
+        assert_eq!(registrations.username(Some("ab")).as_deref(), Some("alice"));
+        assert!(handle_request(Some("open%20sesame"), Some("cd"), &settings, &registrations).await.contains("already been used"));
+        assert!(handle_request(None, Some("ab"), &settings, &registrations).await.contains("registered as alice"));
+
+        let reopened = Registrations::open(&settings).unwrap();
+        assert_eq!(reopened.username(Some("AB")).as_deref(), Some("alice"));
+        assert_eq!(reopened.username(Some("cd")), None);
+        std::fs::remove_file(&store).unwrap();
+    }
+
+    // Test which usernames are accepted
+    #[test]
+    fn test_is_valid_username() {
+        assert!(is_valid_username("alice"));
+        assert!(!is_valid_username(""));
+        assert!(!is_valid_username("alice smith"));
+        assert!(!is_valid_username("alice\n"));
+    }
+}
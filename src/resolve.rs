@@ -0,0 +1,162 @@
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+
+// This module only depends on std, percent-encoding and serde, so the fuzz targets in `fuzz/` can include it directly.
+
+/// Hidden path components that are served anyway: `.well-known` (RFC 8615).
+const ALWAYS_VISIBLE: &[&str] = &[".well-known"];
+
+/// How symlinks inside pages_dir are followed when resolving a request.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Symlinks are never followed.
+    Never,
+    /// Symlinks are followed as long as their target is inside pages_dir.
+    #[default]
+    Inside,
+    /// Symlinks are followed wherever they point.
+    Always,
+}
+
+/// Why a request path was refused.
+#[derive(Debug, PartialEq)]
+pub enum PathError {
+    /// The path is malformed: answered with `59`.
+    Invalid(&'static str),
+    /// The path names a hidden file or directory: answered as if it did not exist.
+    Hidden,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathError::Invalid(reason) => write!(f, "Invalid path: {}", reason),
+            PathError::Hidden => write!(f, "Not Found"),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+/// Sanitizes the requested path to prevent directory traversal attacks.
+/// It percent-decodes the path and refuses invalid UTF-8, NUL bytes, backslashes, other control
+/// characters and parent directory ("..") components. Components starting with a dot are refused
+/// as hidden unless `allow_hidden` is set, apart from `.well-known`.
+pub fn sanitize_path(path: &str, allow_hidden: bool) -> Result<String, PathError> {
+    let decoded = percent_decode_str(path).decode_utf8()
+        .map_err(|_| PathError::Invalid("not valid UTF-8"))?;
+    if decoded.contains('\0') {
+        return Err(PathError::Invalid("NUL bytes are not allowed"));
+    }
+    if decoded.contains('\\') {
+        return Err(PathError::Invalid("backslashes are not allowed"));
+    }
+    if decoded.chars().any(char::is_control) {
+        return Err(PathError::Invalid("control characters are not allowed"));
+    }
+    for component in decoded.split('/') {
+        if component == ".." {
+            return Err(PathError::Invalid("directory traversal is not allowed"));
+        }
+        if !allow_hidden && component.starts_with('.') && component != "." && !ALWAYS_VISIBLE.contains(&component) {
+            return Err(PathError::Hidden);
+        }
+    }
+    Ok(decoded.into_owned())
+}
+
+/// Resolves a file below `root`, given relative to it, checking every component on the way:
+/// symlinks are followed according to `symlinks`, and unless they may point anywhere the result
+/// is always inside the canonical root. Returns the canonical path of the file.
+pub fn resolve_file(root: &Path, relative: &str, symlinks: SymlinkPolicy) -> io::Result<PathBuf> {
+    let root = root.canonicalize()?;
+    let mut path = root.clone();
+    for component in Path::new(relative).components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::RootDir | Component::CurDir => continue,
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} leaves the pages directory", relative)));
+            },
+        }
+        if !std::fs::symlink_metadata(&path)?.file_type().is_symlink() {
+            continue;
+        }
+        path = match symlinks {
+            SymlinkPolicy::Never => {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is a symlink", path.display())));
+            },
+            SymlinkPolicy::Inside => {
+                let target = path.canonicalize()?;
+                if !target.starts_with(&root) {
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{} points outside the pages directory", path.display())));
+                }
+                target
+            },
+            SymlinkPolicy::Always => path.canonicalize()?,
+        };
+    }
+    Ok(path)
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+
+    // Test that ordinary paths are kept as they are
+    #[test]
+    fn test_sanitize_valid_path() {
+        assert_eq!(sanitize_path("/about", false).unwrap(), "/about");
+        assert_eq!(sanitize_path("/caf%C3%A9", false).unwrap(), "/café");
+        assert_eq!(sanitize_path("/.well-known/geser/status", false).unwrap(), "/.well-known/geser/status");
+        assert_eq!(sanitize_path("/./about", false).unwrap(), "/./about");
+    }
+
+    // Test that traversal and malformed paths are refused as bad requests
+    #[test]
+    fn test_sanitize_directory_traversal() {
+        assert!(sanitize_path("/../secret", false).is_err());
+        assert!(matches!(sanitize_path("/a/%2e%2e/%2e%2e/secret", true), Err(PathError::Invalid(_))));
+        assert!(matches!(sanitize_path("/about%00.md", false), Err(PathError::Invalid(_))));
+        assert!(matches!(sanitize_path("/..%5Csecret", false), Err(PathError::Invalid(_))));
+        assert!(matches!(sanitize_path("/a%0Ab", false), Err(PathError::Invalid(_))));
+        assert!(matches!(sanitize_path("/%FF%FE", false), Err(PathError::Invalid(_))), "Invalid UTF-8 is not replaced");
+    }
+
+    // Test the hidden file rule
+    #[test]
+    fn test_sanitize_hidden() {
+        assert_eq!(sanitize_path("/.git/config", false), Err(PathError::Hidden));
+        assert_eq!(sanitize_path("/members/.geser-access", false), Err(PathError::Hidden));
+        assert_eq!(sanitize_path("/%2Egit", false), Err(PathError::Hidden));
+        assert_eq!(sanitize_path("/.git/config", true).unwrap(), "/.git/config");
+    }
+
+    // Test resolving files and following symlinks by policy
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_file() {
+        let base = std::env::temp_dir().join(format!("geser-resolve-{}", std::process::id()));
+        let root = base.join("pages");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("docs/page.md"), "page").unwrap();
+        std::fs::write(base.join("secret.md"), "secret").unwrap();
+        std::os::unix::fs::symlink(root.join("docs"), root.join("alias")).unwrap();
+        std::os::unix::fs::symlink(base.join("secret.md"), root.join("escape.md")).unwrap();
+        let canonical = root.canonicalize().unwrap();
+
+        assert_eq!(resolve_file(&root, "/docs/page.md", SymlinkPolicy::Never).unwrap(), canonical.join("docs/page.md"));
+        assert!(resolve_file(&root, "/alias/page.md", SymlinkPolicy::Never).is_err());
+        assert_eq!(resolve_file(&root, "/alias/page.md", SymlinkPolicy::Inside).unwrap(), canonical.join("docs/page.md"));
+        assert!(resolve_file(&root, "/escape.md", SymlinkPolicy::Inside).is_err(), "Symlinks out of pages_dir are refused");
+        assert!(resolve_file(&root, "/escape.md", SymlinkPolicy::Always).is_ok());
+        assert!(resolve_file(&root, "/../secret.md", SymlinkPolicy::Always).is_err());
+        assert_eq!(resolve_file(&root, "/missing.md", SymlinkPolicy::Inside).unwrap_err().kind(), io::ErrorKind::NotFound);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -1,126 +1,596 @@
-use std::net::SocketAddr;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader as AsyncBufReader};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
 use tokio_rustls::TlsAcceptor;
 use anyhow::{Result, anyhow};
+use percent_encoding::percent_decode_str;
 use url::Url;
-use crate::pages::serve_static_file;
-use crate::pages::serve_markdown;
 
 
-use crate::tls::{get_tls_config, reload_tls_config_task};
+use crate::tls::{cert_fingerprint, certificate_expiry_task, get_tls_config, leaf_info, reload_tls_config_task};
 use crate::pages;
-use crate::config::Settings;
+use crate::titan;
+use crate::access;
+use crate::registration::{self, Registrations};
+use crate::admin::{self, AdminContext};
+use crate::listener::{Connection, Listeners, Peer, Transport};
+use crate::systemd::{self, PassedSocket};
+use crate::privileges::{drop_privileges, drops_privileges};
+use crate::proxy::{self, ProxyHeader};
+use crate::spartan;
+use crate::gopher;
+use crate::http;
+use crate::config::{config_reload_task, Rotation, Settings, UpstreamPass};
 use crate::cache::Cache;
-use crate::util::sanitize_path;
+use crate::metrics::{self, ActiveConnection, METRICS};
+use crate::accesslog::{AccessEntry, AccessLog, ResponseRecorder};
+use crate::ratelimit::{ConnectionCounter, Decision, RateLimiter};
+use crate::resolve::{sanitize_path, PathError};
 
+/// How often clients whose rate-limit buckets have refilled are forgotten.
+const RATE_LIMIT_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
 
-/// Starts the Gemini Server, binds to the listening address, and handles incoming connections.
-pub async fn run_server(settings: Settings) -> Result<()> {
-    // Start the TLS hot reload task (periodically reload certificates)
-    let tls_reload_interval = settings.tls_reload_interval_secs;
-    tokio::spawn(reload_tls_config_task(
-        settings.cert_path.clone(),
-        settings.key_path.clone(),
-        tls_reload_interval,
-    ));
 
+/// Starts the Gemini Server, binds to the listening address (or takes over the `passed_sockets`
+/// from systemd socket activation), and handles incoming connections until `shutdown` completes. It then stops accepting and waits up to `shutdown_timeout_secs`
+/// for in-flight connections to finish before closing them.
+pub async fn run_server(mut settings: Settings, passed_sockets: Vec<PassedSocket>, shutdown: impl Future<Output = ()>) -> Result<()> {
     // Get the initial TLS configuration
-    let tls_config = get_tls_config(&settings.cert_path, &settings.key_path).await?;
-    let acceptor = TlsAcceptor::from(tls_config);
+    let passphrase = settings.key_passphrase()?;
+    let tls_config = get_tls_config(&settings.cert_path, &settings.key_path, passphrase.as_deref()).await?;
+    let (tls_sender, tls_config) = watch::channel(tls_config);
 
-    // Bind listening address
-    let listener = TcpListener::bind(&settings.address).await
-        .map_err(|e| anyhow!("Failed to bind to address {}: {:?}", settings.address, e))?;
-    tracing::info!("Gemini Server started, listening on: {}", settings.address);
+    let (tls_reload, tls_reload_requests) = mpsc::unbounded_channel();
+    let (certificate_sender, certificate) = watch::channel(leaf_info(&settings.cert_path));
+
+    // Bind the listening addresses and Unix socket, unless systemd passed the sockets to use
+    let mut listeners = Listeners::bind(&settings, passed_sockets).await?;
+    tracing::info!("Gemini Server started, listening on: {}", listeners.describe().join(", "));
+
+    // Bind the optional listeners and open the access log too, so that all of them can use
+    // privileged ports and paths before privileges are dropped
+    let spartan_listener = bind_optional("Spartan", settings.spartan.enabled, &settings.spartan.address).await;
+    let gopher_listener = bind_optional("Gopher", settings.gopher.enabled, &settings.gopher.address).await;
+    let http_listener = bind_optional("HTTP preview", settings.http.enabled, &settings.http.address).await;
+    let metrics_listener = bind_optional("metrics", settings.metrics.enabled, &settings.metrics.address).await;
+    let mut access_log_settings = settings.access_log.clone();
+    if drops_privileges(&settings) && access_log_settings.rotation != Rotation::Never {
+        // Rotating renames and reopens the file by path, which may be out of reach afterwards
+        tracing::warn!("Access log rotation is disabled when dropping privileges; rotate the log externally instead");
+        access_log_settings.rotation = Rotation::Never;
+    }
+    let access_log = AccessLog::open(&access_log_settings)?;
+    let registrations = Registrations::open(&settings.registration)?;
+    drop_privileges(&mut settings)?;
+
+    // Start the TLS hot reload task (reloads certificates when they change, on SIGHUP or from the admin page).
+    // Once privileges are dropped the certificate and key may no longer be readable by path
+    let mut background_tasks = vec![tokio::spawn(certificate_expiry_task(settings.cert_path.clone(), certificate.clone()))];
+    if settings.privileges_dropped {
+        tracing::warn!("TLS reloads are disabled after dropping privileges; restart the server to load a new certificate");
+    } else {
+        background_tasks.push(tokio::spawn(reload_tls_config_task(
+            settings.cert_path.clone(),
+            settings.key_path.clone(),
+            passphrase,
+            settings.tls_reload_interval_secs,
+            tls_sender,
+            tls_reload_requests,
+            certificate_sender,
+        )));
+    }
 
     // Create a global cache (for static files and Markdown pages)
     let cache = Cache::new();
+    let (settings_sender, settings) = watch::channel(Arc::new(settings));
 
-    loop {
-        let (stream, peer) = listener.accept().await?;
-        let acceptor = acceptor.clone();
-        let pages_dir = settings.pages_dir.clone();
+    // Start the configuration reload task (reloads config.toml when it changes or on SIGHUP)
+    background_tasks.push(tokio::spawn(config_reload_task(settings_sender)));
+    let startup_settings = settings.borrow().clone();
+
+    // Start the optional Spartan listener, serving the same pages
+    if let Some(listener) = spartan_listener {
+        let settings = settings.clone();
         let cache = cache.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(acceptor, stream, peer, pages_dir, cache).await {
-                tracing::error!("Error handling connection {}: {:?}", peer, e);
+        background_tasks.push(tokio::spawn(async move {
+            if let Err(e) = spartan::run_spartan_server(listener, settings, cache).await {
+                tracing::error!("Spartan server failed: {:?}", e);
             }
-        });
+        }));
+    }
+
+    // Start the optional Gopher gateway, serving the same pages
+    if let Some(listener) = gopher_listener {
+        let settings = settings.clone();
+        let cache = cache.clone();
+        background_tasks.push(tokio::spawn(async move {
+            if let Err(e) = gopher::run_gopher_server(listener, settings, cache).await {
+                tracing::error!("Gopher server failed: {:?}", e);
+            }
+        }));
+    }
+
+    // Start the optional HTTP preview listener, serving the pages as HTML
+    if let Some(listener) = http_listener {
+        let settings = settings.clone();
+        let cache = cache.clone();
+        background_tasks.push(tokio::spawn(async move {
+            if let Err(e) = http::run_http_server(listener, settings, cache).await {
+                tracing::error!("HTTP preview server failed: {:?}", e);
+            }
+        }));
+    }
+
+    // Start the optional Prometheus metrics listener
+    if let Some(listener) = metrics_listener {
+        let cache = cache.clone();
+        background_tasks.push(tokio::spawn(async move {
+            if let Err(e) = metrics::run_metrics_server(listener, cache).await {
+                tracing::error!("Metrics server failed: {:?}", e);
+            }
+        }));
+    }
+
+    // Forget rate-limited clients once their buckets have refilled
+    let rate_limiter = RateLimiter::new();
+    {
+        let rate_limiter = rate_limiter.clone();
+        let settings = settings.clone();
+        background_tasks.push(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RATE_LIMIT_PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                rate_limiter.prune(&settings.borrow().rate_limit);
+            }
+        }));
+    }
+
+    let context = ConnectionContext {
+        cache: cache.clone(),
+        rate_limiter: rate_limiter.clone(),
+        client_connections: ConnectionCounter::new(),
+        access_log,
+        admin: AdminContext::new(tls_reload, certificate),
+        registrations,
+    };
+    let connection_slots = Arc::new(Semaphore::new(startup_settings.limits.max_connections));
+    let mut connections = JoinSet::new();
+
+    // Tell systemd the server is ready, then keep its status current and feed the watchdog
+    if systemd::is_notify_enabled() {
+        systemd::notify(&format!("READY=1\nSTATUS=Listening on {}", listeners.describe().join(", ")));
+        background_tasks.push(tokio::spawn(systemd::notify_task()));
+    }
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = async {
+                // While at the connection limit, stop accepting and leave new clients queued in the listen backlog
+                let permit = connection_slots.clone().acquire_owned().await;
+                (permit, listeners.accept().await)
+            } => {
+                let (permit, accepted) = accepted;
+                let permit = permit?;
+                let (stream, peer, transport) = accepted?;
+                // Each connection uses the TLS configuration and settings that are current when it is accepted
+                let acceptor = TlsAcceptor::from(tls_config.borrow().clone());
+                let settings = settings.borrow().clone();
+                let context = context.clone();
+                connections.spawn(async move {
+                    if let Err(e) = handle_connection(acceptor, stream, peer, transport, settings, context).await {
+                        tracing::error!("Error handling connection {}: {:?}", peer, e);
+                    }
+                    // The connection's slot is released once it is done
+                    drop(permit);
+                });
+            },
+            // Reap finished connections so the set only holds in-flight ones
+            Some(_) = connections.join_next(), if !connections.is_empty() => {},
+            _ = &mut shutdown => break,
+        }
+    }
+
+    // Stop accepting, then give in-flight connections until the deadline to finish
+    drop(listeners);
+    for task in background_tasks {
+        task.abort();
+    }
+    let deadline = Duration::from_secs(settings.borrow().shutdown_timeout_secs);
+    tracing::info!("Shutting down, waiting up to {:?} for {} connection(s) to finish", deadline, connections.len());
+    systemd::notify(&format!("STOPPING=1\nSTATUS=Shutting down, waiting for {} connection(s)", connections.len()));
+    let drained = tokio::time::timeout(deadline, async {
+        while connections.join_next().await.is_some() {}
+    }).await;
+    if drained.is_err() {
+        tracing::warn!("Closing {} connection(s) still open after the shutdown deadline", connections.len());
+        connections.shutdown().await;
+    }
+    let access_log = context.access_log.clone();
+    tokio::task::spawn_blocking(move || access_log.flush()).await?;
+    tracing::info!("Gemini Server stopped");
+    Ok(())
+}
+
+/// Binds the listener of an optional protocol if it is enabled. A listener that cannot be bound
+/// is logged and left out, without stopping the Gemini server.
+async fn bind_optional(name: &str, enabled: bool, address: &str) -> Option<TcpListener> {
+    if !enabled {
+        return None;
+    }
+    match TcpListener::bind(address).await {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            tracing::error!("Failed to bind {} listener to {}: {:?}", name, address, e);
+            None
+        }
     }
 }
 
+/// Completes when the process receives SIGTERM or SIGINT (Ctrl-C).
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => { terminate.recv().await; },
+            Err(e) => {
+                tracing::warn!("Cannot listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            if let Err(e) = result {
+                tracing::warn!("Cannot listen for SIGINT: {}", e);
+                std::future::pending::<()>().await;
+            }
+            tracing::info!("Received SIGINT");
+        },
+        _ = terminate => tracing::info!("Received SIGTERM"),
+    }
+}
+
+/// State shared by all connections on the Gemini listener.
+#[derive(Clone)]
+struct ConnectionContext {
+    cache: Cache,
+    rate_limiter: RateLimiter,
+    client_connections: ConnectionCounter,
+    access_log: AccessLog,
+    admin: AdminContext,
+    registrations: Registrations,
+}
+
 /// Handles a single connection: performs TLS handshake, reads the request line,
 /// sanitizes the requested path, and returns either a Markdown page or a static file.
+/// `titan://` requests are handed to the Titan upload handler instead, and requests
+/// under the admin path to the admin page, and requests for the registration page to the
+/// certificate registration handler. Connections from trusted load balancers start
+/// with a PROXY protocol header naming the client, which is then used in place of `peer`.
 async fn handle_connection(
     acceptor: TlsAcceptor,
-    stream: tokio::net::TcpStream,
-    peer: SocketAddr,
-    pages_dir: String,
-    cache: Cache,
+    stream: Connection,
+    peer: Peer,
+    transport: Transport,
+    settings: Arc<Settings>,
+    context: ConnectionContext,
 ) -> Result<()> {
+    let started = Instant::now();
+    let mut stream = stream;
+    let upstream = transport == Transport::Upstream;
+    // Unix socket clients are guarded by the socket's file permissions rather than by address
+    if let (true, Some(ip)) = (upstream, peer.ip()) {
+        if !proxy::is_trusted(&settings.upstream.trusted, ip) {
+            tracing::warn!("Dropping upstream connection from untrusted address {}", peer);
+            return Ok(());
+        }
+    }
+
+    // Behind a load balancer, the client's address comes from the PROXY protocol header;
+    // a TLS-terminating upstream may also pass the client's certificate and SNI in it
+    let expects_header = (upstream && settings.upstream.pass == UpstreamPass::Proxy)
+        || (settings.proxy_protocol.enabled && peer.ip().is_none_or(|ip| proxy::is_trusted(&settings.proxy_protocol.trusted, ip)));
+    let header = if expects_header {
+        timeout(Duration::from_secs(settings.limits.handshake_timeout_secs), proxy::read_header(&mut stream)).await
+            .map_err(|_| anyhow!("Timed out reading the PROXY header from {}", peer))?
+            .map_err(|e| anyhow!("Invalid PROXY header from {}: {:?}", peer, e))?
+    } else {
+        ProxyHeader::default()
+    };
+    // Unix socket clients have no address unless a PROXY header names the real client, so
+    // address rules refuse them and the per-client limits do not apply
+    let peer = header.source.map_or(peer, Peer::Ip);
+
+    // Banned clients are dropped before spending a handshake on them
+    if peer.ip().is_some_and(|ip| context.rate_limiter.is_banned(ip)) {
+        tracing::debug!("Dropping connection from banned client {}", peer);
+        return Ok(());
+    }
+    let max_per_client = settings.limits.max_connections_per_ip;
+    let _client_guard = match peer.ip() {
+        Some(ip) => {
+            let Some(guard) = context.client_connections.try_acquire(ip, max_per_client) else {
+                tracing::warn!("Dropping connection from {}: already {} open connections from this client", peer, max_per_client);
+                return Ok(());
+            };
+            Some(guard)
+        },
+        None => None,
+    };
     tracing::info!("Handling connection from {}", peer);
-    let tls_stream = acceptor.accept(stream).await
-        .map_err(|e| anyhow!("TLS handshake with {} failed: {:?}", peer, e))?;
+    let _active = ActiveConnection::new();
+
+    if upstream {
+        let client = Client {
+            peer,
+            fingerprint: header.tlv(settings.upstream.cert_tlv).and_then(proxy::fingerprint_from_tlv),
+            sni: header.tlv(proxy::PP2_TYPE_AUTHORITY).map(|sni| String::from_utf8_lossy(sni).to_string()),
+        };
+        let identity_headers = settings.upstream.pass == UpstreamPass::Header;
+        return serve_request(stream, client, identity_headers, settings, context, started).await;
+    }
+
+    let tls_stream = match timeout(Duration::from_secs(settings.limits.handshake_timeout_secs), acceptor.accept(stream)).await {
+        Ok(Ok(tls_stream)) => tls_stream,
+        Ok(Err(e)) => {
+            metrics::increment(&METRICS.handshake_failures);
+            return Err(anyhow!("TLS handshake with {} failed: {:?}", peer, e));
+        },
+        Err(_) => {
+            metrics::increment(&METRICS.handshake_failures);
+            return Err(anyhow!("TLS handshake with {} timed out", peer));
+        },
+    };
+    let session = tls_stream.get_ref().1;
+    let client = Client {
+        peer,
+        fingerprint: session.peer_certificates().and_then(|certs| certs.first()).map(cert_fingerprint),
+        sni: session.sni_hostname().map(str::to_string),
+    };
+    serve_request(tls_stream, client, false, settings, context, started).await
+}
+
+/// Who is behind a connection, as seen by the TLS handshake or passed by a TLS-terminating upstream.
+struct Client {
+    /// Without an address (on the Unix socket) the per-client rate limit does not apply.
+    peer: Peer,
+    /// SHA-256 fingerprint of the client certificate, if one was presented.
+    fingerprint: Option<String>,
+    sni: Option<String>,
+}
 
-    let (reader, mut writer) = tokio::io::split(tls_stream);
+/// Reads the request and sends the response: the pipeline shared by TLS and upstream connections.
+/// With `identity_headers`, the request line is preceded by `Client-Cert:` and `SNI:` lines from
+/// the upstream, ended by an empty line.
+async fn serve_request<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    mut client: Client,
+    identity_headers: bool,
+    settings: Arc<Settings>,
+    context: ConnectionContext,
+    started: Instant,
+) -> Result<()> {
+    let ConnectionContext { cache, rate_limiter, access_log, admin, registrations, .. } = context;
+    let peer = client.peer;
+    let pages_dir = &settings.pages_dir;
+
+    let (reader, writer) = tokio::io::split(stream);
+    let mut writer = ResponseRecorder::new(writer);
     let mut buf_reader = AsyncBufReader::new(reader);
     let mut request_line = String::new();
 
-    let bytes_read = buf_reader.read_line(&mut request_line).await?;
+    if identity_headers {
+        timeout(Duration::from_secs(settings.limits.request_timeout_secs), read_identity_headers(&mut buf_reader, &mut client)).await
+            .map_err(|_| anyhow!("Timed out reading the upstream headers from {}", peer))??;
+    }
+    if let Some(sni) = &client.sni {
+        tracing::debug!("Connection {} asked for {}", peer, sni);
+    }
+    let client_fingerprint = client.fingerprint;
+    let user = registrations.username(client_fingerprint.as_deref());
+
+    let bytes_read = timeout(Duration::from_secs(settings.limits.request_timeout_secs), buf_reader.read_line(&mut request_line)).await
+        .map_err(|_| anyhow!("Timed out reading the request from {}", peer))??;
     if bytes_read == 0 {
         tracing::info!("Connection {} closed", peer);
         return Ok(());
     }
-    tracing::info!("Received request from {}: {}", peer, request_line.trim_end());
-
     let req_line = request_line.trim();
-    let req_url = Url::parse(req_line)
-        .map_err(|e| anyhow!("Failed to parse URL {}: {:?}", req_line, e))?;
-    let path = req_url.path();
-
-    // Perform security checks on URL paths to prevent directory traversal
-    let safe_path = sanitize_path(path)?;
-
-    if safe_path.ends_with(".jpg") || safe_path.ends_with(".jpeg") ||
-       safe_path.ends_with(".png") || safe_path.ends_with(".gif") {
-        // Static image resource request
-        match pages::serve_static_file(&pages_dir, &safe_path, cache).await {
-            Ok((data, mime)) => {
-                let header = format!("20 {}\r\n", mime);
-                writer.write_all(header.as_bytes()).await?;
-                writer.write_all(&data).await?;
+    let parsed_url = Url::parse(req_line);
+    match &user {
+        Some(user) => tracing::info!("Received request from {} ({}): {}", peer, user, logged_request(&parsed_url)),
+        None => tracing::info!("Received request from {}: {}", peer, logged_request(&parsed_url)),
+    }
+
+    // Everything after the request line, including receiving a Titan upload, must finish within the write timeout
+    let response = async {
+        let decision = match peer.ip() {
+            Some(ip) => rate_limiter.check(ip, &settings.rate_limit),
+            None => Decision::Allow,
+        };
+        match decision {
+            Decision::Allow => {},
+            Decision::SlowDown(seconds) => {
+                writer.write_all(format!("44 {}\r\n", seconds).as_bytes()).await?;
+                writer.flush().await?;
+                return Ok(());
             },
+            Decision::Banned => return Ok(()),
+        }
+
+        let req_url = parsed_url.as_ref()
+            .map_err(|e| anyhow!("Failed to parse the request URL from {}: {:?}", peer, e))?;
+
+        if req_url.scheme() == "titan" {
+            let uploader = titan::Uploader { ip: peer.ip(), fingerprint: client_fingerprint.as_deref(), user: user.as_deref() };
+            let response = match titan::handle_upload(&mut buf_reader, req_url, &settings, uploader, &cache).await {
+                Ok(response) => response,
+                Err(e) => {
+                    tracing::error!("Error handling Titan upload {}: {:?}", req_line, e);
+                    "40 Failed to store upload\r\n".to_string()
+                }
+            };
+            writer.write_all(response.as_bytes()).await?;
+            writer.flush().await?;
+            return Ok(());
+        }
+
+        let path = req_url.path();
+
+        // Perform security checks on URL paths to prevent directory traversal
+        let safe_path = match sanitize_path(path, settings.paths.allow_hidden) {
+            Ok(safe_path) => safe_path,
             Err(e) => {
-                tracing::error!("Error serving static resource {}: {:?}", safe_path, e);
-                writer.write_all(b"51 Not Found\r\n").await?;
+                tracing::warn!("Refused path {:?} from {}: {}", path, peer, e);
+                let response = match e {
+                    PathError::Invalid(_) => format!("59 {}\r\n", e),
+                    PathError::Hidden => "51 Not Found\r\n".to_string(),
+                };
+                writer.write_all(response.as_bytes()).await?;
+                writer.flush().await?;
+                return Ok(());
             }
+        };
+
+        let verdict = match access::check(&settings.access, pages_dir, &safe_path, settings.paths.symlinks, peer.ip(), client_fingerprint.as_deref(), user.as_deref()).await {
+            Ok(verdict) => verdict,
+            Err(e) => {
+                tracing::error!("Error checking access to {}: {:?}", safe_path, e);
+                writer.write_all(b"40 Access rules unavailable\r\n").await?;
+                writer.flush().await?;
+                return Ok(());
+            }
+        };
+        if let Some(response) = verdict.response() {
+            writer.write_all(response.as_bytes()).await?;
+            writer.flush().await?;
+            return Ok(());
         }
-    } else {
-        // Markdown page request
-        match pages::serve_markdown(&pages_dir, &safe_path, cache).await {
-            Ok(content) => {
+
+        if admin::is_admin_path(&settings.admin, &safe_path) {
+            let response = admin::handle_request(&safe_path, client_fingerprint.as_deref(), &settings, &cache, &admin);
+            writer.write_all(response.as_bytes()).await?;
+            writer.flush().await?;
+            return Ok(());
+        }
+
+        if registration::is_registration_path(&settings.registration, &safe_path) {
+            let response = registration::handle_request(req_url.query(), client_fingerprint.as_deref(), &settings.registration, &registrations).await;
+            writer.write_all(response.as_bytes()).await?;
+            writer.flush().await?;
+            return Ok(());
+        }
+
+        match pages::lookup(pages_dir, &safe_path, cache, settings.paths.symlinks).await {
+            Ok(pages::Page::Static(data, mime)) => {
+                // Static image resource request
+                let header = format!("20 {}\r\n", mime);
+                writer.write_all(header.as_bytes()).await?;
+                writer.write_all(&data).await?;
+            },
+            Ok(pages::Page::Gemtext(content)) => {
+                // Markdown page request
                 writer.write_all(b"20 text/gemini\r\n").await?;
-                writer.write_all(content.as_bytes()).await?;
+                let query = req_url.query().map(|query| percent_decode_str(query).decode_utf8_lossy());
+                let content = pages::fill_input(&pages::fill_username(&content, user.as_deref()), query.as_deref());
+                writer.write_all(pages::rewrite_prompt_links(&content).as_bytes()).await?;
             },
             Err(e) => {
-                tracing::error!("Error serving page {}: {:?}", safe_path, e);
+                tracing::error!("Error serving {}: {:?}", safe_path, e);
                 writer.write_all(b"51 Not Found\r\n").await?;
             }
         }
+        writer.flush().await?;
+        Ok::<(), anyhow::Error>(())
+    };
+    let result = match timeout(Duration::from_secs(settings.limits.write_timeout_secs), response).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("Timed out sending the response to {}", peer)),
+    };
+
+    let (host, path) = logged_target(&parsed_url);
+    METRICS.record_request(writer.status(), writer.bytes(), started.elapsed());
+    access_log.record(AccessEntry::new(
+        peer.ip(),
+        host,
+        path,
+        writer.status(),
+        writer.bytes(),
+        started.elapsed(),
+        client_fingerprint.as_deref(),
+    ).with_user(user.as_deref()));
+    result
+}
+
+// Host and path of a request as written to the access log. Titan parameters are left out, as they
+// carry upload tokens, and request lines that are not URLs are logged as `-`.
+fn logged_target(parsed_url: &Result<Url, url::ParseError>) -> (Option<&str>, &str) {
+    match parsed_url {
+        Ok(url) if url.scheme() == "titan" => (url.host_str(), url.path().split(';').next().unwrap_or_default()),
+        Ok(url) => (url.host_str(), url.path()),
+        Err(_) => (None, "-"),
     }
-    writer.flush().await?;
-    Ok(())
+}
+
+// The request as written to the server log: the URL without its query, which may be an invite
+// code or other private input, and without Titan parameters
+fn logged_request(parsed_url: &Result<Url, url::ParseError>) -> String {
+    let Ok(url) = parsed_url else { return "invalid URL".to_string() };
+    let mut url = url.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+    let (_, path) = logged_target(parsed_url);
+    url.set_path(path);
+    url.to_string()
+}
+
+/// Most identity header lines accepted from the upstream ahead of a request.
+const MAX_IDENTITY_HEADERS: usize = 16;
+
+// Reads the `Name: value` lines an upstream sends ahead of the request, up to an empty line
+async fn read_identity_headers<R: AsyncBufRead + Unpin>(reader: &mut R, client: &mut Client) -> Result<()> {
+    for _ in 0..MAX_IDENTITY_HEADERS {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(anyhow!("Connection closed before the request"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(());
+        }
+        // A request line here means the upstream did not send the header block
+        let Some((name, value)) = line.split_once(':').filter(|(_, value)| !value.starts_with("//")) else {
+            return Err(anyhow!("Malformed upstream header: {}", line));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("Client-Cert") {
+            client.fingerprint = match value {
+                "" => None,
+                _ => Some(proxy::fingerprint_from_tlv(value.as_bytes()).ok_or_else(|| anyhow!("Invalid upstream Client-Cert: {}", value))?),
+            };
+        } else if name.eq_ignore_ascii_case("SNI") {
+            client.sni = Some(value.to_string()).filter(|value| !value.is_empty());
+        }
+    }
+    Err(anyhow!("Too many upstream headers"))
 }
 
 // Test module
 #[cfg(test)]
 mod tests {
     use super::*;  // Import outer module contents
-    use crate::cache::Cache;
-    use crate::config::Settings;
-    use tokio::net::TcpListener;
-    use std::net::SocketAddr;
+    use std::path::Path;
+    use crate::pages::{serve_markdown, serve_static_file};
     use tokio::fs;
 
     // Test handling of incoming connections
@@ -133,11 +603,12 @@ mod tests {
             key_path: "test_key.pem".to_string(),
             pages_dir: "test_pages".to_string(),
             tls_reload_interval_secs: 300,
+            ..Settings::default()
         };
 
         // Start the server in a separate task
         tokio::spawn(async move {
-            if let Err(e) = run_server(settings).await {
+            if let Err(e) = run_server(settings, Vec::new(), std::future::pending()).await {
                 tracing::error!("Server failed to start: {:?}", e);
             }
         });
@@ -151,6 +622,161 @@ mod tests {
         assert!(client.peer_addr().is_ok());
     }
 
+    // Test that shutdown stops accepting and waits for in-flight connections up to the deadline
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let address = format!("127.0.0.1:{}", port);
+        let settings = Settings {
+            address: address.clone(),
+            cert_path: "test_cert.pem".to_string(),
+            key_path: "test_key.pem".to_string(),
+            pages_dir: "pages".to_string(),
+            tls_reload_interval_secs: 300,
+            shutdown_timeout_secs: 1,
+            ..Settings::default()
+        };
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(run_server(settings, Vec::new(), async { let _ = stopped.await; }));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // A client that never completes its handshake keeps a connection in flight
+        let _client = tokio::net::TcpStream::connect(&address).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let started = std::time::Instant::now();
+        stop.send(()).unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), server).await
+            .expect("The server should stop once the deadline has passed").unwrap();
+        assert!(result.is_ok());
+        assert!(started.elapsed() >= Duration::from_millis(900), "In-flight connections should be waited for");
+        assert!(tokio::net::TcpStream::connect(&address).await.is_err(), "The listener should be closed");
+    }
+
+    // Test that idle clients are timed out and extra connections from one client are refused
+    #[tokio::test]
+    async fn test_connection_limits() {
+        use tokio::io::AsyncReadExt;
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let address = format!("127.0.0.1:{}", port);
+        let mut settings = Settings {
+            address: address.clone(),
+            cert_path: "test_cert.pem".to_string(),
+            key_path: "test_key.pem".to_string(),
+            pages_dir: "pages".to_string(),
+            tls_reload_interval_secs: 300,
+            ..Settings::default()
+        };
+        settings.limits.handshake_timeout_secs = 1;
+        settings.limits.max_connections_per_ip = 1;
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(run_server(settings, Vec::new(), async { let _ = stopped.await; }));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let mut idle = tokio::net::TcpStream::connect(&address).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut extra = tokio::net::TcpStream::connect(&address).await.unwrap();
+        let mut buf = [0u8; 16];
+        let closed = tokio::time::timeout(Duration::from_millis(500), extra.read(&mut buf)).await;
+        assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))), "A second connection from the same client should be closed");
+
+        let started = std::time::Instant::now();
+        let closed = tokio::time::timeout(Duration::from_secs(3), idle.read(&mut buf)).await;
+        assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))), "A client that never completes the handshake should be dropped");
+        assert!(started.elapsed() >= Duration::from_millis(500));
+
+        stop.send(()).unwrap();
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    // Test that upstream clients on a Unix socket are not held to the per-client connection limit,
+    // and that address rules refuse them since they have no address
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_upstream_unix_socket() {
+        use tokio::io::AsyncReadExt;
+        let socket_path = std::env::temp_dir().join(format!("geser-upstream-{}.sock", std::process::id()));
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut settings = Settings {
+            address: format!("127.0.0.1:{}", port),
+            cert_path: "test_cert.pem".to_string(),
+            key_path: "test_key.pem".to_string(),
+            pages_dir: "pages".to_string(),
+            tls_reload_interval_secs: 300,
+            ..Settings::default()
+        };
+        settings.limits.max_connections_per_ip = 1;
+        settings.upstream.enabled = true;
+        settings.upstream.unix_socket = Some(socket_path.to_string_lossy().to_string());
+        settings.upstream.trusted = Vec::new();
+        settings.upstream.pass = UpstreamPass::Header;
+        settings.access = vec![crate::config::AccessRule {
+            prefix: "/private".to_string(),
+            allow: vec!["127.0.0.1/32".parse().unwrap()],
+            ..Default::default()
+        }];
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(run_server(settings, Vec::new(), async { let _ = stopped.await; }));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let _idle = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut client = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        client.write_all(b"\r\ngemini://localhost/missing\r\n").await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(3), client.read_to_string(&mut response)).await.unwrap().unwrap();
+        assert!(response.starts_with("51 "), "A second Unix socket client should be served, got {:?}", response);
+
+        let mut client = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        client.write_all(b"\r\ngemini://localhost/private\r\n").await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(3), client.read_to_string(&mut response)).await.unwrap().unwrap();
+        assert!(response.starts_with("53 "), "Unix socket clients should not pass as 127.0.0.1, got {:?}", response);
+
+        stop.send(()).unwrap();
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    // Test reading the identity lines sent by an upstream in header mode
+    #[tokio::test]
+    async fn test_read_identity_headers() {
+        let new_client = || Client { peer: Peer::Ip("127.0.0.1:1".parse().unwrap()), fingerprint: None, sni: None };
+        let headers = format!("Client-Cert: {}\r\nsni: example.org\r\n\r\ngemini://example.org/\r\n", "AB".repeat(32));
+        let mut reader = tokio::io::BufReader::new(headers.as_bytes());
+        let mut client = new_client();
+        read_identity_headers(&mut reader, &mut client).await.unwrap();
+        assert_eq!(client.fingerprint, Some("ab".repeat(32)));
+        assert_eq!(client.sni.as_deref(), Some("example.org"));
+        let mut rest = String::new();
+        reader.read_line(&mut rest).await.unwrap();
+        assert_eq!(rest, "gemini://example.org/\r\n", "The request line is left for the request reader");
+
+        let mut client = new_client();
+        let mut missing = tokio::io::BufReader::new(&b"gemini://example.org/\r\n"[..]);
+        assert!(read_identity_headers(&mut missing, &mut client).await.is_err(), "The header block is required");
+        let mut invalid = tokio::io::BufReader::new(&b"Client-Cert: nothex\r\n\r\n"[..]);
+        assert!(read_identity_headers(&mut invalid, &mut client).await.is_err());
+    }
+
+    // Test that secrets and malformed request lines stay out of the access log
+    #[test]
+    fn test_logged_target() {
+        let titan = Url::parse("titan://example.org/notes/today;size=12;mime=text/gemini;token=secret");
+        assert_eq!(logged_target(&titan), (Some("example.org"), "/notes/today"));
+        let gemini = Url::parse("gemini://example.org/about");
+        assert_eq!(logged_target(&gemini), (Some("example.org"), "/about"));
+        assert_eq!(logged_target(&Url::parse("not a url at all")), (None, "-"));
+    }
+
+    // Test that invite codes, other input and Titan tokens stay out of the server log
+    #[test]
+    fn test_logged_request() {
+        let register = Url::parse("gemini://example.org:1965/register?invite-code#top");
+        assert_eq!(logged_request(&register), "gemini://example.org:1965/register");
+        let titan = Url::parse("titan://example.org/notes/today;size=12;token=secret");
+        assert_eq!(logged_request(&titan), "titan://example.org/notes/today");
+        assert_eq!(logged_request(&Url::parse("secret code")), "invalid URL");
+    }
+
     // Test request handling with static files
     #[tokio::test]
     async fn test_static_file_handling() {
@@ -164,7 +790,7 @@ mod tests {
         fs::write(&file_path, &data).await.unwrap();
 
         // Test serving the static file
-        let result = serve_static_file(pages_dir, safe_path, cache).await;
+        let result = serve_static_file(Path::new(&file_path), cache).await;
         assert!(result.is_ok(), "The static file should be served correctly");
         let (served_data, mime_type) = result.unwrap();
         assert_eq!(mime_type, "image/jpeg");
@@ -176,7 +802,7 @@ mod tests {
     async fn test_markdown_handling() {
         let cache = Cache::new();
         let pages_dir = "test_pages";
-        let safe_path = "/index.md";
+        let safe_path = "/";
 
         // Create a simple test Markdown file
         let file_path = format!("{}/index.md", pages_dir);
@@ -184,7 +810,7 @@ mod tests {
         fs::write(&file_path, content).await.unwrap();
 
         // Test serving the Markdown file
-        let result = serve_markdown(pages_dir, safe_path, cache).await;
+        let result = serve_markdown(Path::new(&pages::markdown_file_path(pages_dir, safe_path)), cache).await;
         assert!(result.is_ok(), "The Markdown file should be served correctly");
         let result_content = result.unwrap();
         assert!(result_content.contains("Hello World"));
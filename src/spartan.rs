@@ -0,0 +1,264 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader as AsyncBufReader};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use anyhow::{Result, anyhow};
+
+use crate::pages;
+use crate::util;
+use crate::access;
+use crate::config::{Settings, SharedSettings};
+use crate::cache::Cache;
+use crate::resolve::sanitize_path;
+
+/// A parsed Spartan request line: `host path content-length`.
+#[derive(Debug, PartialEq)]
+pub struct SpartanRequest {
+    pub host: String,
+    pub path: String,
+    pub content_length: usize,
+}
+
+/// Parses a Spartan request line such as `example.org /about 0`.
+pub fn parse_request(line: &str) -> Result<SpartanRequest> {
+    let mut parts = line.split(' ');
+    let (host, path, length) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(host), Some(path), Some(length), None) if !host.is_empty() => (host, path, length),
+        _ => return Err(anyhow!("Malformed Spartan request: {}", line)),
+    };
+    if !path.starts_with('/') {
+        return Err(anyhow!("Spartan path must be absolute: {}", path));
+    }
+    let content_length = length.parse::<usize>()
+        .map_err(|_| anyhow!("Invalid Spartan content length: {}", length))?;
+    Ok(SpartanRequest {
+        host: host.to_string(),
+        path: path.to_string(),
+        content_length,
+    })
+}
+
+/// Starts the plaintext Spartan listener, serving the same pages as the Gemini listener.
+pub async fn run_spartan_server(listener: TcpListener, settings: SharedSettings, cache: Cache) -> Result<()> {
+    let address = listener.local_addr()?;
+    tracing::info!("Spartan Server started, listening on: {}", address);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        // Each connection uses the settings that are current when it is accepted
+        let settings = settings.borrow().clone();
+        let cache = cache.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_spartan_connection(stream, peer, settings, cache).await {
+                tracing::error!("Error handling Spartan connection {}: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+/// Handles a single Spartan connection: reads the request line and any uploaded data,
+/// then serves the page with Spartan status codes (2 success, 3 redirect, 4 client error, 5 server error).
+async fn handle_spartan_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    peer: SocketAddr,
+    settings: Arc<Settings>,
+    cache: Cache,
+) -> Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut buf_reader = AsyncBufReader::new(reader);
+    let mut request_line = String::new();
+
+    let request_timeout = Duration::from_secs(settings.limits.request_timeout_secs);
+    let bytes_read = util::read_request_line(&mut buf_reader, &mut request_line, request_timeout).await
+        .map_err(|e| anyhow!("Invalid Spartan request from {}: {}", peer, e))?;
+    if bytes_read == 0 {
+        tracing::info!("Spartan connection {} closed", peer);
+        return Ok(());
+    }
+    tracing::info!("Received Spartan request from {}: {}", peer, request_line.trim_end());
+
+    let request = match parse_request(request_line.trim_end_matches(['\r', '\n'])) {
+        Ok(request) => request,
+        Err(e) => {
+            writer.write_all(format!("4 {}\r\n", e).as_bytes()).await?;
+            writer.flush().await?;
+            return Ok(());
+        }
+    };
+
+    // Uploaded data is the Spartan equivalent of a Gemini query string, sent to input pages.
+    if request.content_length > settings.spartan.max_upload_size {
+        writer.write_all(format!("4 Upload exceeds the maximum size of {} bytes\r\n", settings.spartan.max_upload_size).as_bytes()).await?;
+        writer.flush().await?;
+        return Ok(());
+    }
+    // Like a Titan upload, the data must arrive within the write timeout
+    let mut data = vec![0; request.content_length];
+    timeout(Duration::from_secs(settings.limits.write_timeout_secs), buf_reader.read_exact(&mut data)).await
+        .map_err(|_| anyhow!("Timed out receiving the Spartan upload from {}", peer))?
+        .map_err(|e| anyhow!("Spartan upload from {} ended early: {:?}", peer, e))?;
+    let Ok(input) = String::from_utf8(data) else {
+        writer.write_all(b"4 Input must be UTF-8 text\r\n").await?;
+        writer.flush().await?;
+        return Ok(());
+    };
+    if request.content_length > 0 {
+        tracing::info!("Received {} bytes of input for {}", request.content_length, request.path);
+    }
+
+    let path = request.path.split('?').next().unwrap_or("/");
+    let safe_path = match sanitize_path(path, settings.paths.allow_hidden) {
+        Ok(safe_path) => safe_path,
+        Err(e) => {
+            writer.write_all(format!("4 {}\r\n", e).as_bytes()).await?;
+            writer.flush().await?;
+            return Ok(());
+        }
+    };
+
+    // Spartan has no client certificates, so paths that need one are refused
+    let verdict = match access::check(&settings.access, &settings.pages_dir, &safe_path, settings.paths.symlinks, Some(peer.ip()), None, None).await {
+        Ok(verdict) => verdict,
+        Err(e) => {
+            tracing::error!("Error checking access to {}: {:?}", safe_path, e);
+            writer.write_all(b"5 Access rules unavailable\r\n").await?;
+            writer.flush().await?;
+            return Ok(());
+        }
+    };
+    if let Some(reason) = verdict.reason() {
+        writer.write_all(format!("4 {}\r\n", reason).as_bytes()).await?;
+        writer.flush().await?;
+        return Ok(());
+    }
+
+    match pages::lookup(&settings.pages_dir, &safe_path, cache, settings.paths.symlinks).await {
+        Ok(pages::Page::Static(data, mime)) => {
+            writer.write_all(format!("2 {}\r\n", mime).as_bytes()).await?;
+            writer.write_all(&data).await?;
+        },
+        Ok(pages::Page::Gemtext(content)) => {
+            // Spartan clients understand `=:` prompt lines, so the content is sent unchanged.
+            // The uploaded input is handed to the page like a Gemini query.
+            let content = pages::fill_input(&pages::fill_username(&content, None), Some(&input));
+            writer.write_all(b"2 text/gemini\r\n").await?;
+            writer.write_all(content.as_bytes()).await?;
+        },
+        Err(e) if !pages::is_not_found(&e) => {
+            tracing::error!("Error serving {} over Spartan: {:?}", safe_path, e);
+            writer.write_all(b"5 Internal server error\r\n").await?;
+        },
+        Err(_) if !path.ends_with('/') && pages::is_directory(&settings.pages_dir, &safe_path, settings.paths.symlinks).await => {
+            writer.write_all(format!("3 {}/\r\n", path).as_bytes()).await?;
+        },
+        Err(e) => {
+            tracing::error!("Error serving {} over Spartan: {:?}", safe_path, e);
+            writer.write_all(b"4 Not Found\r\n").await?;
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+    use tokio::fs;
+    use crate::config::AccessRule;
+
+    // Test parsing of Spartan request lines
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(parse_request("example.org /about 0").unwrap(), SpartanRequest {
+            host: "example.org".to_string(),
+            path: "/about".to_string(),
+            content_length: 0,
+        });
+        assert_eq!(parse_request("localhost / 12").unwrap().content_length, 12);
+        assert!(parse_request("example.org about 0").is_err());
+        assert!(parse_request("example.org /about").is_err());
+        assert!(parse_request("example.org /about -1").is_err());
+    }
+
+    // Send a raw request over an in-memory stream and return the full response
+    async fn request(settings: Settings, raw: &[u8]) -> String {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let peer: SocketAddr = "127.0.0.1:300".parse().unwrap();
+        let task = tokio::spawn(handle_spartan_connection(server, peer, Arc::new(settings), Cache::new()));
+        client.write_all(raw).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        task.await.unwrap().unwrap();
+        response
+    }
+
+    // Test that request lines are capped in length and must arrive in time
+    #[tokio::test]
+    async fn test_spartan_request_limits() {
+        let peer: SocketAddr = "127.0.0.1:300".parse().unwrap();
+        let (mut client, server) = tokio::io::duplex(4096);
+        let task = tokio::spawn(handle_spartan_connection(server, peer, Arc::new(Settings::default()), Cache::new()));
+        client.write_all(&[b'a'; 2048]).await.unwrap();
+        assert!(task.await.unwrap().is_err(), "Overlong request lines are refused");
+
+        let mut settings = Settings::default();
+        settings.limits.request_timeout_secs = 1;
+        let (_client, server) = tokio::io::duplex(4096);
+        let result = tokio::time::timeout(Duration::from_secs(3), handle_spartan_connection(server, peer, Arc::new(settings), Cache::new())).await;
+        assert!(result.expect("Idle clients are timed out").is_err());
+    }
+
+    // Test serving a page, prompt lines intact, with and without uploaded input
+    #[tokio::test]
+    async fn test_spartan_page() {
+        let pages_dir = std::env::temp_dir().join(format!("geser-spartan-{}", std::process::id()));
+        fs::create_dir_all(&pages_dir).await.unwrap();
+        fs::write(pages_dir.join("guestbook.md"), "# Guestbook\n\n=: /guestbook Sign it\n\nThanks, {{input}}!").await.unwrap();
+        fs::create_dir_all(pages_dir.join("docs")).await.unwrap();
+        fs::write(pages_dir.join("docs/index.md"), "# Docs").await.unwrap();
+        let settings = || Settings {
+            pages_dir: pages_dir.to_string_lossy().to_string(),
+            ..Settings::default()
+        };
+
+        let response = request(settings(), b"localhost /guestbook 0\r\n").await;
+        assert!(response.starts_with("2 text/gemini\r\n"));
+        assert!(response.contains("=: /guestbook Sign it"));
+
+        let response = request(settings(), b"localhost /guestbook 5\r\nhello").await;
+        assert!(response.starts_with("2 text/gemini\r\n"));
+        assert!(response.contains("Thanks, hello!"), "Uploaded input is handed to the page");
+        let response = request(settings(), b"localhost /guestbook 0\r\n").await;
+        assert!(response.contains("Thanks, !"));
+
+        let response = request(settings(), b"localhost /docs 0\r\n").await;
+        assert_eq!(response, "3 /docs/\r\n", "Directories are redirected to their trailing slash");
+        let response = request(settings(), b"localhost /docs/ 0\r\n").await;
+        assert!(response.starts_with("2 text/gemini\r\n"));
+
+        let response = request(settings(), b"localhost /missing 0\r\n").await;
+        assert_eq!(response, "4 Not Found\r\n");
+
+        let response = request(settings(), b"localhost /../secret 0\r\n").await;
+        assert!(response.starts_with("4 "));
+
+        // Without client certificates, paths that need one are refused
+        fs::write(pages_dir.join("docs").join(access::ACCESS_FILE), "require_cert = true\n").await.unwrap();
+        let response = request(settings(), b"localhost /docs/ 0\r\n").await;
+        assert_eq!(response, "4 Client certificate required\r\n");
+        let mut club = settings();
+        club.access = vec![AccessRule { prefix: "/guestbook".to_string(), users: vec!["alice".to_string()], ..AccessRule::default() }];
+        let response = request(club, b"localhost /guestbook 0\r\n").await;
+        assert_eq!(response, "4 Client certificate required\r\n");
+        fs::remove_file(pages_dir.join("docs").join(access::ACCESS_FILE)).await.unwrap();
+
+        // Failing to read a page that exists is the server's fault
+        fs::create_dir_all(pages_dir.join("broken.md")).await.unwrap();
+        let response = request(settings(), b"localhost /broken 0\r\n").await;
+        assert_eq!(response, "5 Internal server error\r\n");
+        fs::remove_dir_all(&pages_dir).await.unwrap();
+    }
+}
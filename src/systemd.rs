@@ -0,0 +1,156 @@
+use std::time::Duration;
+
+use crate::metrics::{self, METRICS};
+
+/// First file descriptor passed by systemd socket activation.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// How often the status string is refreshed when no watchdog is configured.
+const STATUS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A socket passed by systemd socket activation, with its name from `FileDescriptorName=`.
+#[cfg(unix)]
+pub type PassedSocket = (std::os::fd::OwnedFd, String);
+/// Socket activation only exists on Unix, so no socket is ever passed elsewhere.
+#[cfg(not(unix))]
+pub type PassedSocket = (std::convert::Infallible, String);
+
+/// Sockets passed by systemd socket activation (`LISTEN_FDS`), if they are meant for this process,
+/// with their names from `LISTEN_FDNAMES`. The variables are removed from the environment so child
+/// processes do not take the sockets too, which is only sound while the process has a single
+/// thread: this must be called in `main` before the runtime starts.
+#[cfg(unix)]
+pub fn listen_fds() -> Vec<PassedSocket> {
+    use std::os::fd::FromRawFd;
+    let count = listen_fds_count(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    let mut names = names.split(':');
+    (0..count).map(|offset| {
+        // SAFETY: systemd passes `count` open sockets starting at LISTEN_FDS_START, owned by this process only
+        let fd = unsafe { std::os::fd::OwnedFd::from_raw_fd(LISTEN_FDS_START + offset as i32) };
+        let name = names.next().filter(|name| !name.is_empty()).unwrap_or("unknown");
+        (fd, name.to_string())
+    }).collect()
+}
+
+#[cfg(not(unix))]
+pub fn listen_fds() -> Vec<PassedSocket> {
+    Vec::new()
+}
+
+// Number of passed sockets, or 0 when they were meant for another process
+fn listen_fds_count(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> usize {
+    match (listen_pid.and_then(|p| p.parse::<u32>().ok()), listen_fds.and_then(|n| n.parse::<usize>().ok())) {
+        (Some(listen_pid), Some(count)) if listen_pid == pid => count,
+        _ => 0,
+    }
+}
+
+/// Sends a state change such as `READY=1` or `STATUS=...` to the service manager.
+/// Does nothing when not started by systemd with `Type=notify`.
+pub fn notify(state: &str) {
+    let Ok(socket) = std::env::var("NOTIFY_SOCKET") else { return };
+    if let Err(e) = notify_to(&socket, state) {
+        tracing::warn!("Failed to notify systemd at {}: {}", socket, e);
+    }
+}
+
+// Sends a datagram to the notification socket; names starting with `@` are in the abstract namespace
+#[cfg(unix)]
+fn notify_to(socket: &str, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+    let datagram = UnixDatagram::unbound()?;
+    if let Some(name) = socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return datagram.send_to_addr(state.as_bytes(), &address).map(|_| ());
+        }
+        #[cfg(not(target_os = "linux"))]
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, format!("abstract socket @{} is not supported", name)));
+    }
+    datagram.send_to(state.as_bytes(), socket).map(|_| ())
+}
+
+#[cfg(not(unix))]
+fn notify_to(_socket: &str, _state: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "systemd notifications need Unix sockets"))
+}
+
+/// Interval at which systemd expects watchdog pings (`WATCHDOG_USEC`), if the watchdog is enabled for this process.
+fn watchdog_interval() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+    match std::env::var("WATCHDOG_PID").ok().map(|pid| pid.parse::<u32>()) {
+        Some(Ok(pid)) if pid != std::process::id() => None,
+        _ => Some(Duration::from_micros(usec)),
+    }
+}
+
+/// Status string shown by `systemctl status`.
+pub fn status_line() -> String {
+    let requests: u64 = METRICS.requests.iter().map(metrics::get).sum();
+    format!(
+        "STATUS=Serving {} connection(s), {} request(s) so far",
+        metrics::get(&METRICS.active_connections),
+        requests
+    )
+}
+
+/// Whether the process was started by systemd with a notification socket.
+pub fn is_notify_enabled() -> bool {
+    std::env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+/// Background task that keeps the status string current and, if the watchdog is enabled,
+/// pings it at half the configured interval.
+pub async fn notify_task() {
+    let watchdog = watchdog_interval();
+    let mut interval = tokio::time::interval(watchdog.map_or(STATUS_INTERVAL, |timeout| timeout / 2));
+    loop {
+        interval.tick().await;
+        let status = status_line();
+        match watchdog {
+            Some(_) => notify(&format!("WATCHDOG=1\n{}", status)),
+            None => notify(&status),
+        }
+    }
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+
+    // Test that passed sockets are only taken when meant for this process
+    #[test]
+    fn test_listen_fds_count() {
+        assert_eq!(listen_fds_count(Some("42"), Some("2"), 42), 2);
+        assert_eq!(listen_fds_count(Some("41"), Some("2"), 42), 0, "Sockets for another process are ignored");
+        assert_eq!(listen_fds_count(None, Some("2"), 42), 0);
+        assert_eq!(listen_fds_count(Some("42"), Some("x"), 42), 0);
+    }
+
+    // Test sending a notification to a socket
+    #[cfg(unix)]
+    #[test]
+    fn test_notify_to() {
+        let path = std::env::temp_dir().join(format!("geser-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        notify_to(&path.to_string_lossy(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let received = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..received], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+        assert!(status_line().starts_with("STATUS=Serving "));
+    }
+}
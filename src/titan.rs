@@ -0,0 +1,404 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use anyhow::{Result, anyhow};
+use percent_encoding::percent_decode_str;
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use url::Url;
+
+use crate::cache::Cache;
+use crate::access;
+use crate::config::{Settings, TitanSettings};
+use crate::pages;
+use crate::resolve::{resolve_file, sanitize_path, PathError, SymlinkPolicy};
+
+/// MIME types accepted for page uploads, which are stored as the page's Markdown file.
+const MARKDOWN_TYPES: &[&str] = &["text/markdown", "text/x-markdown"];
+
+// Counter used to give each temporary upload file a unique name.
+static UPLOAD_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A parsed Titan request: the target path and the `;`-separated parameters that follow it.
+#[derive(Debug, PartialEq)]
+pub struct TitanRequest {
+    pub path: String,
+    pub size: usize,
+    pub mime: String,
+    pub token: Option<String>,
+}
+
+/// Parses the path of a `titan://` URL, e.g. `/notes/today;size=12;mime=text/gemini;token=secret`.
+/// The `size` parameter is required; `mime` defaults to `text/gemini`.
+pub fn parse_titan_path(path: &str) -> Result<TitanRequest> {
+    let mut parts = path.split(';');
+    let target = parts.next().unwrap_or("/");
+    let mut size = None;
+    let mut mime = None;
+    let mut token = None;
+    for param in parts {
+        let (key, value) = param.split_once('=')
+            .ok_or_else(|| anyhow!("Malformed Titan parameter: {}", param))?;
+        let value = percent_decode_str(value).decode_utf8_lossy().to_string();
+        match key {
+            "size" => size = Some(value.parse::<usize>()
+                .map_err(|_| anyhow!("Invalid Titan size: {}", value))?),
+            "mime" => mime = Some(value),
+            "token" => token = Some(value),
+            // Unknown parameters are ignored so newer clients keep working.
+            _ => {}
+        }
+    }
+    Ok(TitanRequest {
+        path: if target.is_empty() { "/".to_string() } else { target.to_string() },
+        size: size.ok_or_else(|| anyhow!("Missing Titan size parameter"))?,
+        mime: mime.unwrap_or_else(|| "text/gemini".to_string()),
+        token,
+    })
+}
+
+/// Checks whether an upload is allowed, either by client certificate fingerprint or by token.
+/// On failure, returns the Gemini response header to send back.
+pub fn authorize(settings: &TitanSettings, fingerprint: Option<&str>, token: Option<&str>) -> std::result::Result<(), &'static str> {
+    if let Some(fingerprint) = fingerprint {
+        if settings.allowed_fingerprints.iter().any(|f| f.eq_ignore_ascii_case(fingerprint)) {
+            return Ok(());
+        }
+    }
+    if let Some(token) = token {
+        if settings.tokens.iter().any(|t| t == token) {
+            return Ok(());
+        }
+    }
+    if fingerprint.is_none() && token.is_none() {
+        Err("60 Client certificate or token required\r\n")
+    } else {
+        Err("61 Not authorized to upload\r\n")
+    }
+}
+
+/// Writes data to a temporary file next to the target and renames it into place,
+/// so readers never observe a partially written page.
+pub async fn write_atomically(file_path: &str, data: &[u8]) -> Result<()> {
+    let target = Path::new(file_path);
+    let parent = target.parent().unwrap_or_else(|| Path::new("."));
+    let file_name = target.file_name()
+        .ok_or_else(|| anyhow!("Invalid upload target: {}", file_path))?
+        .to_string_lossy();
+    fs::create_dir_all(parent).await
+        .map_err(|e| anyhow!("Failed to create directory {}: {:?}", parent.display(), e))?;
+
+    let tmp_path = parent.join(format!(
+        ".{}.titan-{}-{}",
+        file_name,
+        std::process::id(),
+        UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result = async {
+        let mut file = fs::File::create(&tmp_path).await?;
+        file.write_all(data).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp_path, target).await
+    }.await;
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp_path).await;
+        return Err(anyhow!("Failed to write upload {}: {:?}", file_path, e));
+    }
+    Ok(())
+}
+
+/// Who is uploading: the client's address, certificate fingerprint and registered username.
+pub struct Uploader<'a> {
+    /// `None` for Unix socket clients, whose address is unknown.
+    pub ip: Option<IpAddr>,
+    pub fingerprint: Option<&'a str>,
+    pub user: Option<&'a str>,
+}
+
+/// Handles a `titan://` request: authorizes it, checks the target against the access rules like a
+/// request for the page would be, reads exactly `size` bytes of body from the reader, stores the
+/// file in pages_dir, in the directory resolved under the symlink policy, and invalidates its cache
+/// entry. Returns the response header to send, which is a redirect to the uploaded page on success.
+pub async fn handle_upload<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    url: &Url,
+    settings: &Settings,
+    uploader: Uploader<'_>,
+    cache: &Cache,
+) -> Result<String> {
+    let pages_dir = settings.pages_dir.as_str();
+    let symlinks = settings.paths.symlinks;
+    let titan = &settings.titan;
+    if !titan.enabled {
+        return Ok("59 Titan uploads are not enabled\r\n".to_string());
+    }
+    let request = match parse_titan_path(url.path()) {
+        Ok(request) => request,
+        Err(e) => return Ok(format!("59 {}\r\n", e)),
+    };
+    if let Err(response) = authorize(titan, uploader.fingerprint, request.token.as_deref()) {
+        return Ok(response.to_string());
+    }
+    if request.size > titan.max_size {
+        return Ok(format!("59 Upload exceeds the maximum size of {} bytes\r\n", titan.max_size));
+    }
+
+    // Uploads go to the same file that serves the corresponding gemini:// URL.
+    // Hidden files are never written, so uploads cannot replace `.geser-access` files
+    let safe_path = match sanitize_path(&request.path, false) {
+        Ok(safe_path) => safe_path,
+        Err(PathError::Hidden) => return Ok("51 Not Found\r\n".to_string()),
+        Err(e) => return Ok(format!("59 {}\r\n", e)),
+    };
+    let verdict = access::check(&settings.access, pages_dir, &safe_path, symlinks, uploader.ip, uploader.fingerprint, uploader.user).await?;
+    if let Some(response) = verdict.response() {
+        return Ok(response.to_string());
+    }
+    // Pages are served from Markdown, so other text, gemtext included, would not come back as uploaded
+    let relative = if pages::is_static_asset(&safe_path) {
+        pages::static_file_path("", &safe_path)
+    } else if request.size == 0 || MARKDOWN_TYPES.contains(&request.mime.as_str()) {
+        pages::markdown_file_path("", &safe_path)
+    } else {
+        return Ok(format!("59 Pages must be uploaded as text/markdown, not {}\r\n", request.mime));
+    };
+    let (directory, file_name) = relative.rsplit_once('/').unwrap_or(("", &relative));
+    let directory = match resolve_upload_dir(pages_dir, directory, symlinks, request.size > 0).await? {
+        Ok(directory) => directory,
+        Err(e) => {
+            tracing::warn!("Refused Titan upload to {}: {}", safe_path, e);
+            return Ok("51 Not Found\r\n".to_string());
+        },
+    };
+    let file_path = directory.join(file_name).to_string_lossy().to_string();
+
+    if request.size == 0 {
+        // A zero-sized upload deletes the resource.
+        if let Err(e) = fs::remove_file(&file_path).await {
+            tracing::warn!("Failed to delete {}: {:?}", file_path, e);
+            return Ok("51 Not Found\r\n".to_string());
+        }
+        tracing::info!("Deleted {} via Titan", file_path);
+    } else {
+        let mut data = vec![0; request.size];
+        if reader.read_exact(&mut data).await.is_err() {
+            return Ok("59 Upload body is shorter than the declared size\r\n".to_string());
+        }
+        write_atomically(&file_path, &data).await?;
+        tracing::info!("Stored {} bytes ({}) at {} via Titan", request.size, request.mime, file_path);
+    }
+    cache.invalidate(&file_path);
+
+    let host = url.host_str().unwrap_or("localhost");
+    let port = url.port().map(|p| format!(":{}", p)).unwrap_or_default();
+    Ok(format!("30 gemini://{}{}{}\r\n", host, port, safe_path))
+}
+
+// Resolves the directory an upload goes to like pages are resolved, following symlinks only as
+// `symlinks` allows. Directories that do not exist yet are created inside the resolved part when
+// `create` is set, along with pages_dir itself.
+async fn resolve_upload_dir(pages_dir: &str, directory: &str, symlinks: SymlinkPolicy, create: bool) -> Result<std::io::Result<PathBuf>> {
+    let root = pages_dir.to_string();
+    let directory = directory.to_string();
+    Ok(tokio::task::spawn_blocking(move || {
+        if create {
+            std::fs::create_dir_all(&root)?;
+        }
+        let components: Vec<&str> = directory.split('/').filter(|component| !component.is_empty() && *component != ".").collect();
+        let mut existing = components.len();
+        let mut resolved = loop {
+            match resolve_file(Path::new(&root), &components[..existing].join("/"), symlinks) {
+                Ok(resolved) => break resolved,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound && existing > 0 && create => existing -= 1,
+                Err(e) => return Err(e),
+            }
+        };
+        for component in &components[existing..] {
+            resolved.push(component);
+            std::fs::create_dir(&resolved)?;
+        }
+        Ok(resolved)
+    }).await?)
+}
+
+// Test module
+#[cfg(test)]
+mod tests {
+    use super::*;  // Import outer module contents
+
+    fn settings() -> TitanSettings {
+        TitanSettings {
+            enabled: true,
+            tokens: vec!["secret".to_string()],
+            allowed_fingerprints: vec!["ABCDEF".to_string()],
+            ..TitanSettings::default()
+        }
+    }
+
+    // Server settings for pages_dir with Titan enabled
+    fn server_settings(pages_dir: &str, symlinks: SymlinkPolicy) -> Settings {
+        let mut server = Settings { pages_dir: pages_dir.to_string(), titan: settings(), ..Settings::default() };
+        server.paths.symlinks = symlinks;
+        server
+    }
+
+    fn anonymous() -> Uploader<'static> {
+        Uploader { ip: "192.0.2.1".parse().ok(), fingerprint: None, user: None }
+    }
+
+    // Test parsing of Titan parameters
+    #[test]
+    fn test_parse_titan_path() {
+        let request = parse_titan_path("/notes/today;size=12;mime=text/plain;token=a%20b").unwrap();
+        assert_eq!(request, TitanRequest {
+            path: "/notes/today".to_string(),
+            size: 12,
+            mime: "text/plain".to_string(),
+            token: Some("a b".to_string()),
+        });
+
+        let request = parse_titan_path("/;size=0").unwrap();
+        assert_eq!(request.mime, "text/gemini");
+        assert!(parse_titan_path("/page").is_err(), "size is required");
+        assert!(parse_titan_path("/page;size=abc").is_err());
+    }
+
+    // Test authorization by fingerprint and token
+    #[test]
+    fn test_authorize() {
+        let settings = settings();
+        assert!(authorize(&settings, Some("abcdef"), None).is_ok());
+        assert!(authorize(&settings, None, Some("secret")).is_ok());
+        assert!(authorize(&settings, Some("123456"), Some("secret")).is_ok());
+        assert_eq!(authorize(&settings, None, None).unwrap_err(), "60 Client certificate or token required\r\n");
+        assert_eq!(authorize(&settings, Some("123456"), Some("wrong")).unwrap_err(), "61 Not authorized to upload\r\n");
+    }
+
+    // Test a full upload: body is read, stored, cache invalidated and redirect returned
+    #[tokio::test]
+    async fn test_handle_upload() {
+        let pages_dir = std::env::temp_dir().join(format!("geser-titan-{}", std::process::id()));
+        let pages_dir = pages_dir.to_string_lossy().to_string();
+        let cache = Cache::new();
+        let file_path = pages::markdown_file_path(&pages_dir, "/notes/today");
+        cache.set_text(file_path.clone(), "stale".to_string());
+
+        let url = Url::parse("titan://example.org:1965/notes/today;size=5;mime=text/markdown;token=secret").unwrap();
+        let mut body: &[u8] = b"# Hi!trailing";
+        let response = handle_upload(&mut body, &url, &server_settings(&pages_dir, SymlinkPolicy::Inside), anonymous(), &cache).await.unwrap();
+
+        assert_eq!(response, "30 gemini://example.org:1965/notes/today\r\n");
+        assert_eq!(fs::read_to_string(&file_path).await.unwrap(), "# Hi!");
+        assert_eq!(cache.get_text(&file_path), None);
+        fs::remove_dir_all(&pages_dir).await.unwrap();
+    }
+
+    // Test that uploads are refused when disabled or too large
+    #[tokio::test]
+    async fn test_handle_upload_rejected() {
+        let cache = Cache::new();
+        let url = Url::parse("titan://example.org/page;size=100;token=secret").unwrap();
+        let mut body: &[u8] = b"";
+
+        let disabled = Settings { titan: TitanSettings { enabled: false, ..settings() }, ..server_settings("unused", SymlinkPolicy::Inside) };
+        let response = handle_upload(&mut body, &url, &disabled, anonymous(), &cache).await.unwrap();
+        assert!(response.starts_with("59 "));
+
+        let small = Settings { titan: TitanSettings { max_size: 10, ..settings() }, ..server_settings("unused", SymlinkPolicy::Inside) };
+        let response = handle_upload(&mut body, &url, &small, anonymous(), &cache).await.unwrap();
+        assert!(response.starts_with("59 "));
+    }
+
+    // Test that uploads stay inside pages_dir and away from hidden files
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_handle_upload_paths() {
+        let base = std::env::temp_dir().join(format!("geser-titan-paths-{}", std::process::id()));
+        let pages_dir = base.join("pages");
+        std::fs::create_dir_all(pages_dir.join("docs")).unwrap();
+        std::fs::create_dir_all(base.join("outside")).unwrap();
+        std::fs::write(base.join("outside/page.md"), "outside").unwrap();
+        std::os::unix::fs::symlink(base.join("outside"), pages_dir.join("escape")).unwrap();
+        std::os::unix::fs::symlink(pages_dir.join("docs"), pages_dir.join("alias")).unwrap();
+        let pages_dir_str = pages_dir.to_string_lossy().to_string();
+        let cache = Cache::new();
+        let upload = |path: &str| Url::parse(&format!("titan://example.org{};mime=text/markdown;token=secret", path)).unwrap();
+
+        let mut body: &[u8] = b"# Escaped";
+        let response = handle_upload(&mut body, &upload("/escape/new;size=9"), &server_settings(&pages_dir_str, SymlinkPolicy::Inside), anonymous(), &cache).await.unwrap();
+        assert_eq!(response, "51 Not Found\r\n", "Symlinks out of pages_dir are not written through");
+        assert!(!base.join("outside/new.md").exists());
+        let response = handle_upload(&mut body, &upload("/escape/page;size=0"), &server_settings(&pages_dir_str, SymlinkPolicy::Inside), anonymous(), &cache).await.unwrap();
+        assert_eq!(response, "51 Not Found\r\n", "Symlinks out of pages_dir are not deleted through");
+        assert!(base.join("outside/page.md").exists());
+
+        let response = handle_upload(&mut body, &upload("/alias/new;size=9"), &server_settings(&pages_dir_str, SymlinkPolicy::Never), anonymous(), &cache).await.unwrap();
+        assert_eq!(response, "51 Not Found\r\n", "The symlink policy applies to uploads");
+        let response = handle_upload(&mut body, &upload("/alias/deeper/new;size=9"), &server_settings(&pages_dir_str, SymlinkPolicy::Inside), anonymous(), &cache).await.unwrap();
+        assert!(response.starts_with("30 "));
+        assert_eq!(std::fs::read_to_string(pages_dir.join("docs/deeper/new.md")).unwrap(), "# Escaped");
+
+        let response = handle_upload(&mut body, &upload("/docs/.geser-access;size=1;mime=text/plain"), &server_settings(&pages_dir_str, SymlinkPolicy::Inside), anonymous(), &cache).await.unwrap();
+        assert_eq!(response, "51 Not Found\r\n");
+        let response = handle_upload(&mut body, &upload("/docs/a%00b;size=1"), &server_settings(&pages_dir_str, SymlinkPolicy::Inside), anonymous(), &cache).await.unwrap();
+        assert!(response.starts_with("59 Invalid path"), "Malformed paths are bad requests, got {:?}", response);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    // Test that uploads are held to the access rules of their target, symlinks included
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_handle_upload_restricted() {
+        let pages_dir = std::env::temp_dir().join(format!("geser-titan-access-{}", std::process::id()));
+        std::fs::create_dir_all(pages_dir.join("members")).unwrap();
+        std::fs::create_dir_all(pages_dir.join("public")).unwrap();
+        std::fs::write(pages_dir.join("members").join(access::ACCESS_FILE), "require_cert = true\n").unwrap();
+        std::fs::write(pages_dir.join("members/page.md"), "# Members").unwrap();
+        std::os::unix::fs::symlink(pages_dir.join("members"), pages_dir.join("public/alias")).unwrap();
+        let pages_dir_str = pages_dir.to_string_lossy().to_string();
+        let settings = server_settings(&pages_dir_str, SymlinkPolicy::Inside);
+        let cache = Cache::new();
+        let upload = |path: &str| Url::parse(&format!("titan://example.org{};mime=text/markdown;token=secret", path)).unwrap();
+
+        for path in ["/members/new;size=5", "/public/alias/new;size=5", "/public/alias/deeper/new;size=5", "/members/page;size=0"] {
+            let mut body: &[u8] = b"# New";
+            let response = handle_upload(&mut body, &upload(path), &settings, anonymous(), &cache).await.unwrap();
+            assert_eq!(response, "60 Client certificate required\r\n", "{} is restricted", path);
+        }
+        assert!(!pages_dir.join("members/new.md").exists());
+        assert!(!pages_dir.join("members/deeper").exists());
+        assert!(pages_dir.join("members/page.md").exists());
+
+        let mut denied = server_settings(&pages_dir_str, SymlinkPolicy::Inside);
+        denied.access = vec![crate::config::AccessRule { prefix: "/members".to_string(), deny: vec!["192.0.2.0/24".parse().unwrap()], ..Default::default() }];
+        std::fs::remove_file(pages_dir.join("members").join(access::ACCESS_FILE)).unwrap();
+        let mut body: &[u8] = b"# New";
+        let response = handle_upload(&mut body, &upload("/public/alias/new;size=5"), &denied, anonymous(), &cache).await.unwrap();
+        assert_eq!(response, "53 Access denied\r\n", "Configured rules apply to the resolved directory");
+        std::fs::remove_dir_all(&pages_dir).unwrap();
+    }
+
+    // Test that only Markdown is accepted for pages, as other text would not be served as uploaded
+    #[tokio::test]
+    async fn test_handle_upload_mime() {
+        let pages_dir = std::env::temp_dir().join(format!("geser-titan-mime-{}", std::process::id()));
+        std::fs::create_dir_all(&pages_dir).unwrap();
+        std::fs::write(pages_dir.join("notes.md"), "# Notes").unwrap();
+        let settings = server_settings(&pages_dir.to_string_lossy(), SymlinkPolicy::Inside);
+        let cache = Cache::new();
+
+        for mime in ["", ";mime=text/gemini", ";mime=text/plain"] {
+            let url = Url::parse(&format!("titan://example.org/notes;size=5{};token=secret", mime)).unwrap();
+            let mut body: &[u8] = b"=> / Home";
+            let response = handle_upload(&mut body, &url, &settings, anonymous(), &cache).await.unwrap();
+            assert!(response.starts_with("59 Pages must be uploaded as text/markdown"), "{:?} is refused, got {:?}", mime, response);
+        }
+        assert_eq!(std::fs::read_to_string(pages_dir.join("notes.md")).unwrap(), "# Notes");
+
+        let url = Url::parse("titan://example.org/notes;size=0;token=secret").unwrap();
+        let response = handle_upload(&mut &b""[..], &url, &settings, anonymous(), &cache).await.unwrap();
+        assert!(response.starts_with("30 "), "Deleting a page needs no MIME type");
+        assert!(!pages_dir.join("notes.md").exists());
+        std::fs::remove_dir_all(&pages_dir).unwrap();
+    }
+}
@@ -1,78 +1,424 @@
 use std::sync::Arc;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Write};
+use std::path::Path;
+use std::time::SystemTime;
 use anyhow::{Result, Context, anyhow};
-use rustls::{Certificate, PrivateKey, ServerConfig};
-use rustls_pemfile;
-use tokio::time::{sleep, Duration};
+use rustls::{Certificate, DistinguishedNames, PrivateKey, ServerConfig, SignatureScheme};
+use rustls::server::{ClientCertVerified, ClientCertVerifier};
+use sha2::{Digest, Sha256};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep, Duration, MissedTickBehavior};
 use tracing::info;
+use crate::certinfo::{CertInfo, EXPIRY_WARNING_DAYS};
+use crate::config::{KeyType, SelfSignedSettings, Settings};
+use crate::metrics::{self, METRICS};
+use crate::util::{watch_files, Hangup};
+
+/// How long to wait after a change to the certificate or key before reloading them.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+/// How often the certificate being served is checked for approaching expiry.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Client certificate verifier that accepts any certificate the client presents.
+/// Gemini clients use self-signed certificates, so they are identified by fingerprint instead of a CA chain.
+struct AcceptAnyClientCert;
+
+impl ClientCertVerifier for AcceptAnyClientCert {
+    fn client_auth_mandatory(&self) -> Option<bool> {
+        Some(false)
+    }
+
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        Some(DistinguishedNames::new())
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+/// Returns the lowercase hex SHA-256 fingerprint of a certificate.
+pub fn cert_fingerprint(cert: &Certificate) -> String {
+    Sha256::digest(&cert.0)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
 
 /// Returns the TLS configuration by reading the certificate and key files.
-pub async fn get_tls_config(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>> {
-    load_tls_config(cert_path, key_path)
+pub async fn get_tls_config(cert_path: &str, key_path: &str, passphrase: Option<&str>) -> Result<Arc<ServerConfig>> {
+    load_tls_config(cert_path, key_path, passphrase)
 }
 
-/// Loads the TLS configuration: reads the certificate chain and private key, and builds the ServerConfig.
-pub fn load_tls_config(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>> {
-    // Read the certificate file.
+/// Reads the certificate chain from a PEM file.
+pub fn load_certs(cert_path: &str) -> Result<Vec<Certificate>> {
     let cert_file = &mut BufReader::new(File::open(cert_path)
         .with_context(|| format!("Failed to open certificate file: {}", cert_path))?);
-    let certs = rustls_pemfile::certs(cert_file)
+    let certs: Vec<Certificate> = rustls_pemfile::certs(cert_file)
         .with_context(|| "Failed to read certificate")?
         .into_iter()
         .map(Certificate)
         .collect();
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates found in {}", cert_path));
+    }
+    Ok(certs)
+}
+
+/// PEM label of a passphrase-protected PKCS#8 key, which rustls_pemfile does not recognise.
+const ENCRYPTED_KEY_LABEL: &str = "ENCRYPTED PRIVATE KEY";
+
+/// Reads every private key from a PEM file: PKCS#8 (RSA, ECDSA or Ed25519), PKCS#1 RSA and SEC1 EC keys,
+/// plus passphrase-protected PKCS#8 keys, which are decrypted with the given passphrase.
+pub fn load_private_keys(key_path: &str, passphrase: Option<&str>) -> Result<Vec<PrivateKey>> {
+    let pem = std::fs::read(key_path)
+        .with_context(|| format!("Failed to open key file: {}", key_path))?;
+    let items = rustls_pemfile::read_all(&mut pem.as_slice())
+        .with_context(|| format!("Failed to read private key from {}", key_path))?;
+    let mut keys: Vec<PrivateKey> = items.into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .collect();
 
-    // Read the private key file, supporting PKCS8 and RSA formats.
-    let key_file = &mut BufReader::new(File::open(key_path)
-        .with_context(|| format!("Failed to open key file: {}", key_path))?);
-    let keys = rustls_pemfile::read_all(key_file)
-        .with_context(|| "Failed to read private key")?;
-    let mut private_key = None;
-    for item in keys {
-        match item {
-            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) => {
-                private_key = Some(PrivateKey(key));
-                break;
-            },
-            _ => continue,
+    let text = String::from_utf8_lossy(&pem);
+    let begin = format!("-----BEGIN {}-----", ENCRYPTED_KEY_LABEL);
+    let end = format!("-----END {}-----", ENCRYPTED_KEY_LABEL);
+    let mut rest = text.as_ref();
+    while let Some(start) = rest.find(&begin) {
+        let stop = rest[start..].find(&end)
+            .map(|offset| start + offset + end.len())
+            .ok_or_else(|| anyhow!("Unterminated encrypted private key in {}", key_path))?;
+        let passphrase = passphrase.ok_or_else(|| anyhow!(
+            "Private key {} is encrypted; set key_passphrase_env or key_passphrase_file", key_path
+        ))?;
+        keys.push(decrypt_private_key(&rest[start..stop], passphrase)
+            .with_context(|| format!("Failed to decrypt private key {}", key_path))?);
+        rest = &rest[stop..];
+    }
+
+    if keys.is_empty() {
+        return Err(anyhow!(
+            "No private key found in {} (expected a PKCS#8, RSA or EC PEM section)", key_path
+        ));
+    }
+    Ok(keys)
+}
+
+/// Decrypts a PEM-encoded encrypted PKCS#8 key (PBES2 with PBKDF2 or scrypt) into a plain PKCS#8 key.
+fn decrypt_private_key(pem: &str, passphrase: &str) -> Result<PrivateKey> {
+    let (_, der) = pkcs8::der::pem::decode_vec(pem.as_bytes())
+        .map_err(|e| anyhow!("Invalid PEM encoding: {}", e))?;
+    let encrypted = pkcs8::EncryptedPrivateKeyInfo::try_from(der.as_slice())
+        .map_err(|e| anyhow!("Invalid encrypted PKCS#8 key: {}", e))?;
+    let decrypted = encrypted.decrypt(passphrase)
+        .map_err(|_| anyhow!("Wrong passphrase or unsupported encryption scheme"))?;
+    Ok(PrivateKey(decrypted.as_bytes().to_vec()))
+}
+
+/// Reads the private keys from key_path and returns the one that belongs to the certificate.
+pub fn load_matching_key(cert: &Certificate, key_path: &str, passphrase: Option<&str>) -> Result<PrivateKey> {
+    let keys = load_private_keys(key_path, passphrase)?;
+    let count = keys.len();
+    let mut last_error = None;
+    for key in keys {
+        match verify_key_matches(cert, &key) {
+            Ok(()) => return Ok(key),
+            Err(e) => last_error = Some(e),
         }
     }
-    let private_key = private_key.ok_or_else(|| anyhow!("No valid private key found"))?;
+    let error = last_error.unwrap_or_else(|| anyhow!("No private key found"));
+    if count == 1 {
+        Err(error)
+    } else {
+        Err(anyhow!("None of the {} private keys in {} matches the certificate", count, key_path))
+    }
+}
+
+/// Checks that the private key belongs to the certificate by signing a test message with the key
+/// and verifying the signature with the certificate's public key.
+pub fn verify_key_matches(cert: &Certificate, key: &PrivateKey) -> Result<()> {
+    let signing_key = rustls::sign::any_supported_type(key)
+        .map_err(|_| anyhow!("Unsupported private key type"))?;
+    let signer = signing_key
+        .choose_scheme(&[
+            SignatureScheme::ED25519,
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::RSA_PSS_SHA256,
+        ])
+        .ok_or_else(|| anyhow!("No supported signature scheme for the private key"))?;
+    let algorithm: &webpki::SignatureAlgorithm = match signer.scheme() {
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        _ => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    };
+
+    let message = b"Geser certificate and private key consistency check";
+    let signature = signer.sign(message)
+        .map_err(|e| anyhow!("Failed to sign with the private key: {:?}", e))?;
+    let end_entity = webpki::EndEntityCert::try_from(cert.0.as_slice())
+        .map_err(|e| anyhow!("Failed to parse certificate: {:?}", e))?;
+    end_entity.verify_signature(algorithm, message, &signature)
+        .map_err(|_| anyhow!("The private key does not match the certificate"))
+}
+
+/// Builds the diagnostics printed by `check-tls`: details of every certificate in the chain
+/// and whether the private key matches the leaf certificate.
+pub fn tls_report(cert_path: &str, key_path: &str, passphrase: Option<&str>) -> Result<String> {
+    let certs = load_certs(cert_path)?;
+    let mut report = String::new();
+    for (index, cert) in certs.iter().enumerate() {
+        let info = CertInfo::parse(cert)?;
+        report.push_str(&format!("Certificate {} of {} in {}\n{}\n", index + 1, certs.len(), cert_path, info.report()));
+        if index == 0 && info.days_until_expiry() < EXPIRY_WARNING_DAYS {
+            report.push_str("Warning: the certificate expires soon or has expired\n");
+        }
+        report.push('\n');
+    }
+    if let Some(warning) = chain_order_warning(&certs)? {
+        report.push_str(&format!("Warning: {}\n\n", warning));
+    }
+    load_matching_key(&certs[0], key_path, passphrase)?;
+    report.push_str(&format!("Private key {} matches the certificate\n", key_path));
+    Ok(report)
+}
+
+/// Checks that each certificate in the chain was issued by the next one, as TLS clients expect.
+fn chain_order_warning(certs: &[Certificate]) -> Result<Option<String>> {
+    let infos = certs.iter().map(CertInfo::parse).collect::<Result<Vec<_>>>()?;
+    for (index, pair) in infos.windows(2).enumerate() {
+        if pair[0].issuer != pair[1].subject {
+            return Ok(Some(format!(
+                "certificate {} is issued by {}, but certificate {} is {}; the chain should run from the leaf up to the root",
+                index + 1, pair[0].issuer, index + 2, pair[1].subject
+            )));
+        }
+    }
+    Ok(None)
+}
+
+/// Loads the TLS configuration: reads the certificate chain (leaf first, then any intermediates)
+/// and the private key that belongs to the leaf, logs the certificate details and builds the ServerConfig.
+pub fn load_tls_config(cert_path: &str, key_path: &str, passphrase: Option<&str>) -> Result<Arc<ServerConfig>> {
+    let certs = load_certs(cert_path)?;
+    let private_key = load_matching_key(&certs[0], key_path, passphrase)
+        .with_context(|| format!("Refusing to load {} with {}", cert_path, key_path))?;
+    CertInfo::parse(&certs[0])?.log(cert_path);
+    if certs.len() > 1 {
+        info!("Serving {} with {} intermediate certificate(s)", cert_path, certs.len() - 1);
+    }
+    if let Some(warning) = chain_order_warning(&certs)? {
+        tracing::warn!("Certificate chain in {}: {}", cert_path, warning);
+    }
 
     let config = ServerConfig::builder()
         .with_safe_defaults()
-        .with_no_client_auth()  // Do not require client certificate.
+        .with_client_cert_verifier(Arc::new(AcceptAnyClientCert))  // Request, but do not require, a client certificate.
         .with_single_cert(certs, private_key)
         .with_context(|| "Failed to build TLS configuration")?;
     Ok(Arc::new(config))
 }
 
-/// Background task that periodically reloads the TLS configuration.
-pub async fn reload_tls_config_task(cert_path: String, key_path: String, interval_secs: u64) {
-    let interval = Duration::from_secs(interval_secs);
+/// Generates a long-lived self-signed certificate with the hostnames as subject alternative names
+/// (IP addresses are added as IP SANs) and writes the certificate and private key as PEM files.
+/// The key file is only readable by its owner. Returns the SHA-256 fingerprint of the new certificate.
+pub fn generate_self_signed(cert_path: &str, key_path: &str, hostnames: &[String], options: &SelfSignedSettings) -> Result<String> {
+    let hostnames = if hostnames.is_empty() { vec!["localhost".to_string()] } else { hostnames.to_vec() };
+    let mut params = rcgen::CertificateParams::default();
+    params.alg = match options.key_type {
+        KeyType::Ecdsa => &rcgen::PKCS_ECDSA_P256_SHA256,
+        KeyType::Ed25519 => &rcgen::PKCS_ED25519,
+    };
+    let now = time::OffsetDateTime::now_utc();
+    params.not_before = now - time::Duration::days(1);
+    params.not_after = now + time::Duration::days(options.validity_days.into());
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params.distinguished_name.push(rcgen::DnType::CommonName, hostnames[0].clone());
+    params.subject_alt_names = hostnames.iter()
+        .map(|name| match name.parse::<std::net::IpAddr>() {
+            Ok(ip) => rcgen::SanType::IpAddress(ip),
+            Err(_) => rcgen::SanType::DnsName(name.clone()),
+        })
+        .collect();
+
+    let cert = rcgen::Certificate::from_params(params)
+        .with_context(|| "Failed to generate certificate")?;
+    let cert_pem = cert.serialize_pem()
+        .with_context(|| "Failed to serialize certificate")?;
+    // Every serialization signs the certificate anew, and ECDSA signatures differ each time,
+    // so the fingerprint is taken from the PEM that is written
+    let cert_der = rustls_pemfile::certs(&mut cert_pem.as_bytes())
+        .with_context(|| "Failed to read the generated certificate")?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No certificate was generated"))?;
+    write_with_mode(cert_path, cert_pem.as_bytes(), 0o644)
+        .with_context(|| format!("Failed to write certificate file: {}", cert_path))?;
+    write_with_mode(key_path, cert.serialize_private_key_pem().as_bytes(), 0o600)
+        .with_context(|| format!("Failed to write key file: {}", key_path))?;
+    Ok(cert_fingerprint(&Certificate(cert_der)))
+}
+
+/// Generates a self-signed certificate if neither cert_path nor key_path exists and generation is enabled.
+/// Returns true if a certificate was generated. Having only one of the two files is an error,
+/// since generating would overwrite it.
+pub fn ensure_certificate(settings: &Settings) -> Result<bool> {
+    let cert_exists = Path::new(&settings.cert_path).exists();
+    let key_exists = Path::new(&settings.key_path).exists();
+    match (cert_exists, key_exists) {
+        (true, true) => Ok(false),
+        (false, false) if settings.self_signed.enabled => {
+            let fingerprint = generate_self_signed(&settings.cert_path, &settings.key_path, &settings.hostnames, &settings.self_signed)?;
+            info!(
+                "Generated self-signed {:?} certificate {} for {:?}, valid for {} days",
+                settings.self_signed.key_type, settings.cert_path, settings.hostnames, settings.self_signed.validity_days
+            );
+            info!("Certificate SHA-256 fingerprint (for TOFU verification): {}", fingerprint);
+            Ok(true)
+        },
+        (false, false) => Ok(false),
+        (true, false) => Err(anyhow!("Certificate {} exists but key {} is missing", settings.cert_path, settings.key_path)),
+        (false, true) => Err(anyhow!("Key {} exists but certificate {} is missing", settings.key_path, settings.cert_path)),
+    }
+}
+
+/// Writes a file, creating it with the given Unix permissions (and tightening them if it already existed).
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn write_with_mode(path: &str, data: &[u8], mode: u32) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(mode))?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// Reloads the TLS configuration and swaps it in, keeping the previous one if the new files do not load.
+/// Returns whether the new configuration was swapped in.
+pub fn reload_tls_config(cert_path: &str, key_path: &str, passphrase: Option<&str>, sender: &watch::Sender<Arc<ServerConfig>>) -> bool {
+    match load_tls_config(cert_path, key_path, passphrase) {
+        Ok(new_config) => {
+            sender.send_replace(new_config);
+            metrics::increment(&METRICS.tls_reloads);
+            info!(
+                "Reloaded TLS configuration from {} and {} ({} reloads, {} failures so far)",
+                cert_path, key_path, metrics::get(&METRICS.tls_reloads), metrics::get(&METRICS.tls_reload_failures)
+            );
+            true
+        },
+        Err(e) => {
+            metrics::increment(&METRICS.tls_reload_failures);
+            tracing::error!(
+                "Failed to reload TLS configuration, keeping the current one ({} failures so far): {:?}",
+                metrics::get(&METRICS.tls_reload_failures), e
+            );
+            false
+        }
+    }
+}
+
+/// Modification times of the certificate and key, used to notice changes the file watcher missed.
+fn modification_times(cert_path: &str, key_path: &str) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &str| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    (modified(cert_path), modified(key_path))
+}
+
+/// Background task that reloads the TLS configuration when the certificate or key file changes,
+/// on SIGHUP and when a reload is requested through `requests` (by the admin page).
+/// As a fallback the files' modification times are also checked every `interval_secs`.
+/// New configurations are only swapped in if they load and the key matches the certificate, and
+/// the details of the new certificate are then sent through `certificate`.
+pub async fn reload_tls_config_task(
+    cert_path: String,
+    key_path: String,
+    passphrase: Option<String>,
+    interval_secs: u64,
+    sender: watch::Sender<Arc<ServerConfig>>,
+    mut requests: mpsc::UnboundedReceiver<()>,
+    certificate: watch::Sender<Option<CertInfo>>,
+) {
+    let (changes_tx, mut changes) = mpsc::unbounded_channel();
+    // The watcher stops when dropped, so it is kept alive for the lifetime of the task
+    let _watcher = match watch_files(&[&cert_path, &key_path], changes_tx) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            tracing::warn!("Cannot watch {} and {} for changes, checking every {} seconds instead: {}", cert_path, key_path, interval_secs, e);
+            None
+        }
+    };
+    let mut hangup = Hangup::new();
+
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval.tick().await;
+    let mut last_modified = modification_times(&cert_path, &key_path);
     loop {
-        sleep(interval).await;
-        match load_tls_config(&cert_path, &key_path) {
-            Ok(new_config) => {
-                // Here  can update the global configuration or notify the relevant modules to reload the TLS configuration.
-                info!("Reloaded TLS configuration: {:?}", new_config);
+        tokio::select! {
+            Some(()) = changes.recv() => {
+                // Editors and renewal tools often write the certificate and key in several steps,
+                // so wait for the changes to settle before reloading
+                sleep(RELOAD_DEBOUNCE).await;
+                while changes.try_recv().is_ok() {}
+                info!("{} or {} changed, reloading TLS configuration", cert_path, key_path);
+            },
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading TLS configuration");
+            },
+            Some(()) = requests.recv() => {
+                info!("TLS reload requested, reloading TLS configuration");
             },
-            Err(e) => {
-                tracing::error!("Failed to reload TLS configuration: {:?}", e);
-            }
+            _ = interval.tick() => {
+                if modification_times(&cert_path, &key_path) == last_modified {
+                    continue;
+                }
+                info!("{} or {} was modified, reloading TLS configuration", cert_path, key_path);
+            },
+        }
+        last_modified = modification_times(&cert_path, &key_path);
+        if reload_tls_config(&cert_path, &key_path, passphrase.as_deref(), &sender) {
+            certificate.send_replace(leaf_info(&cert_path));
+        }
+    }
+}
+
+/// Background task that checks the certificate being served for approaching expiry once a day.
+pub async fn certificate_expiry_task(cert_path: String, certificate: watch::Receiver<Option<CertInfo>>) {
+    let mut expiry_check = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+    expiry_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick is immediate, and loading the certificate has just logged its expiry
+    expiry_check.tick().await;
+    loop {
+        expiry_check.tick().await;
+        if let Some(info) = certificate.borrow().as_ref() {
+            info.log_expiry(&cert_path);
         }
     }
 }
 
+/// Details of the leaf certificate in cert_path, or `None` if it cannot be read.
+pub fn leaf_info(cert_path: &str) -> Option<CertInfo> {
+    load_certs(cert_path).ok()?.first().and_then(|cert| CertInfo::parse(cert).ok())
+}
+
 // Test module
 #[cfg(test)]
 mod tests {
     use super::*;  // Import functions and structures from outer module
-    use std::env;
-    use std::fs::File;
     use std::path::Path;
-    use tokio;
 
     // Test that TLS config is loaded correctly
     #[tokio::test]
@@ -86,7 +432,7 @@ mod tests {
         assert!(Path::new(key_file).exists(), "Key file does not exist");
 
         // Test loading the TLS configuration
-        let result = get_tls_config(cert_file, key_file).await;
+        let result = get_tls_config(cert_file, key_file, None).await;
         assert!(result.is_ok(), "Failed to load TLS config");
     }
 
@@ -105,8 +451,10 @@ mod tests {
         let interval_secs = 1;
 
         // Run the reload task and check if the configuration reloads without errors
+        let (sender, _receiver) = watch::channel(load_tls_config(cert_file, key_file, None).unwrap());
+        let (_requests, requests_rx) = mpsc::unbounded_channel();
         let task = tokio::spawn(async move {
-            reload_tls_config_task(cert_file.to_string(), key_file.to_string(), interval_secs).await;
+            reload_tls_config_task(cert_file.to_string(), key_file.to_string(), None, interval_secs, sender, requests_rx, watch::channel(None).0).await;
         });
 
         // Allow the task to run for a few seconds
@@ -114,6 +462,182 @@ mod tests {
         task.abort(); // Stop the task after the test period
 
         // Check if the task ran correctly (this is a basic test, you can expand it by checking logs or other effects)
-        assert!(true, "TLS reload task ran successfully");
+        assert!(task.await.unwrap_err().is_cancelled(), "TLS reload task should run until aborted");
+    }
+
+    // Test that changed files are swapped in and broken ones are not
+    #[tokio::test]
+    async fn test_reload_on_file_change() {
+        let dir = std::env::temp_dir().join(format!("geser-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem").to_string_lossy().to_string();
+        let key_path = dir.join("key.pem").to_string_lossy().to_string();
+        let options = SelfSignedSettings::default();
+        generate_self_signed(&cert_path, &key_path, &[], &options).unwrap();
+
+        let (sender, mut receiver) = watch::channel(load_tls_config(&cert_path, &key_path, None).unwrap());
+        let initial = receiver.borrow_and_update().clone();
+        let failures = metrics::get(&METRICS.tls_reload_failures);
+        let (requests, requests_rx) = mpsc::unbounded_channel();
+        let (certificate, served) = watch::channel(leaf_info(&cert_path));
+        let task = tokio::spawn(reload_tls_config_task(cert_path.clone(), key_path.clone(), None, 60, sender, requests_rx, certificate));
+        sleep(Duration::from_millis(200)).await;
+
+        generate_self_signed(&cert_path, &key_path, &[], &options).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), receiver.changed()).await
+            .expect("A changed certificate should be reloaded").unwrap();
+        let reloaded = receiver.borrow_and_update().clone();
+        assert!(!Arc::ptr_eq(&initial, &reloaded));
+        let fingerprint = |info: Option<CertInfo>| info.map(|info| info.fingerprint);
+        assert_eq!(fingerprint(served.borrow().clone()), fingerprint(leaf_info(&cert_path)), "The served certificate should be published");
+
+        std::fs::write(&key_path, "not a key").unwrap();
+        sleep(RELOAD_DEBOUNCE * 3).await;
+        assert!(!receiver.has_changed().unwrap(), "A broken key should not be swapped in");
+        assert!(metrics::get(&METRICS.tls_reload_failures) > failures, "The failed reload should be counted");
+
+        // Requested reloads happen without the files changing
+        generate_self_signed(&cert_path, &key_path, &[], &options).unwrap();
+        receiver.changed().await.unwrap();
+        receiver.borrow_and_update();
+        requests.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), receiver.changed()).await
+            .expect("A requested reload should be swapped in").unwrap();
+
+        task.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Test that a generated certificate can be loaded
+    #[test]
+    fn test_generate_self_signed() {
+        let dir = std::env::temp_dir().join(format!("geser-gen-cert-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem").to_string_lossy().to_string();
+        let key_path = dir.join("key.pem").to_string_lossy().to_string();
+
+        let hostnames = ["example.org".to_string(), "127.0.0.1".to_string()];
+        for key_type in [KeyType::Ecdsa, KeyType::Ed25519] {
+            let options = SelfSignedSettings { key_type, ..SelfSignedSettings::default() };
+            let fingerprint = generate_self_signed(&cert_path, &key_path, &hostnames, &options).unwrap();
+            assert_eq!(fingerprint, cert_fingerprint(&load_certs(&cert_path).unwrap()[0]), "The fingerprint should be that of the written certificate");
+            assert!(load_tls_config(&cert_path, &key_path, None).is_ok(), "Generated {:?} certificate should load", key_type);
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&key_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600, "Key file should only be readable by its owner");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Test that a certificate is only generated when both files are missing
+    #[test]
+    fn test_ensure_certificate() {
+        let dir = std::env::temp_dir().join(format!("geser-ensure-cert-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let settings = Settings {
+            cert_path: dir.join("cert.pem").to_string_lossy().to_string(),
+            key_path: dir.join("key.pem").to_string_lossy().to_string(),
+            ..Settings::default()
+        };
+
+        assert!(ensure_certificate(&settings).unwrap(), "Missing files should be generated");
+        assert!(!ensure_certificate(&settings).unwrap(), "Existing files should be kept");
+        std::fs::remove_file(&settings.key_path).unwrap();
+        assert!(ensure_certificate(&settings).is_err(), "A lone certificate should not be overwritten");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Test that a key from another certificate is refused
+    #[test]
+    fn test_mismatched_key_refused() {
+        let dir = std::env::temp_dir().join(format!("geser-mismatch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let options = SelfSignedSettings::default();
+        generate_self_signed(&path("a.pem"), &path("a.key"), &[], &options).unwrap();
+        generate_self_signed(&path("b.pem"), &path("b.key"), &[], &options).unwrap();
+
+        assert!(load_tls_config(&path("a.pem"), &path("a.key"), None).is_ok());
+        assert!(load_tls_config(&path("a.pem"), &path("b.key"), None).is_err(), "Mismatched key should be refused");
+        let report = tls_report(&path("a.pem"), &path("a.key"), None).unwrap();
+        assert!(report.contains("SHA-256 fingerprint"));
+        assert!(report.contains("matches the certificate"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Test SEC1 EC keys, key files holding several keys and passphrase-protected PKCS#8 keys
+    #[test]
+    fn test_load_private_key_formats() {
+        use pkcs8::der::pem::{encode_string, LineEnding};
+        let dir = std::env::temp_dir().join(format!("geser-key-formats-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let cert = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec!["localhost".to_string()])).unwrap();
+        let other = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec!["localhost".to_string()])).unwrap();
+        std::fs::write(path("cert.pem"), cert.serialize_pem().unwrap()).unwrap();
+        let pkcs8_der = cert.serialize_private_key_der();
+
+        // SEC1 ("EC PRIVATE KEY") is the private key wrapped inside the PKCS#8 structure
+        let sec1 = pkcs8::PrivateKeyInfo::try_from(pkcs8_der.as_slice()).unwrap().private_key.to_vec();
+        std::fs::write(path("sec1.key"), encode_string("EC PRIVATE KEY", LineEnding::LF, &sec1).unwrap()).unwrap();
+        assert!(load_tls_config(&path("cert.pem"), &path("sec1.key"), None).is_ok(), "SEC1 keys should load");
+
+        // The key matching the certificate is picked, wherever it is in the file
+        std::fs::write(path("both.key"), other.serialize_private_key_pem() + &cert.serialize_private_key_pem()).unwrap();
+        assert!(load_tls_config(&path("cert.pem"), &path("both.key"), None).is_ok(), "The matching key should be chosen");
+        std::fs::write(path("other.key"), other.serialize_private_key_pem() + &other.serialize_private_key_pem()).unwrap();
+        let error = load_tls_config(&path("cert.pem"), &path("other.key"), None).unwrap_err();
+        assert!(format!("{:#}", error).contains("None of the 2 private keys"));
+
+        let params = pkcs8::pkcs5::pbes2::Parameters::pbkdf2_sha256_aes256cbc(2048, b"saltsalt", &[7; 16]).unwrap();
+        let encrypted = pkcs8::PrivateKeyInfo::try_from(pkcs8_der.as_slice()).unwrap()
+            .encrypt_with_params(params, "hunter2").unwrap();
+        std::fs::write(path("encrypted.key"), encode_string("ENCRYPTED PRIVATE KEY", LineEnding::LF, encrypted.as_bytes()).unwrap()).unwrap();
+        assert!(load_tls_config(&path("cert.pem"), &path("encrypted.key"), Some("hunter2")).is_ok());
+        let error = load_tls_config(&path("cert.pem"), &path("encrypted.key"), None).unwrap_err();
+        assert!(format!("{:#}", error).contains("is encrypted"), "Missing passphrase: {:#}", error);
+        let error = load_tls_config(&path("cert.pem"), &path("encrypted.key"), Some("wrong")).unwrap_err();
+        assert!(format!("{:#}", error).contains("Wrong passphrase"), "Wrong passphrase: {:#}", error);
+
+        std::fs::write(path("empty.key"), "").unwrap();
+        assert!(load_private_keys(&path("empty.key"), None).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Test serving a leaf certificate followed by an intermediate
+    #[test]
+    fn test_certificate_chain() {
+        let dir = std::env::temp_dir().join(format!("geser-chain-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+        let mut ca_params = rcgen::CertificateParams::new(Vec::new());
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(rcgen::DnType::CommonName, "Geser Test Intermediate");
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+        let leaf = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec!["localhost".to_string()])).unwrap();
+        let leaf_pem = leaf.serialize_pem_with_signer(&ca).unwrap();
+        let ca_pem = ca.serialize_pem().unwrap();
+        std::fs::write(path("leaf.key"), leaf.serialize_private_key_pem()).unwrap();
+
+        std::fs::write(path("chain.pem"), leaf_pem.clone() + &ca_pem).unwrap();
+        assert!(load_tls_config(&path("chain.pem"), &path("leaf.key"), None).is_ok());
+        let report = tls_report(&path("chain.pem"), &path("leaf.key"), None).unwrap();
+        assert!(report.contains("Certificate 2 of 2"));
+        assert!(!report.contains("the chain should run"), "An ordered chain should not warn");
+
+        std::fs::write(path("reversed.pem"), ca_pem + &leaf_pem).unwrap();
+        assert!(load_tls_config(&path("reversed.pem"), &path("leaf.key"), None).is_err(), "The leaf must come first");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Test certificate fingerprint formatting
+    #[test]
+    fn test_cert_fingerprint() {
+        let fingerprint = cert_fingerprint(&Certificate(b"abc".to_vec()));
+        assert_eq!(fingerprint, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
     }
 }
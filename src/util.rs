@@ -1,34 +1,80 @@
 use anyhow::{Result, anyhow};
-use percent_encoding::percent_decode_str;
-use std::path::{Path, Component};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::mpsc;
 
-/// Sanitizes the requested path to prevent directory traversal attacks.
-/// It decodes URL-encoded characters and ensures the path does not contain any parent directory ("..") references.
-pub fn sanitize_path(path: &str) -> Result<String> {
-    let decoded = percent_decode_str(path).decode_utf8_lossy();
-    let path = Path::new(&*decoded);
-    for component in path.components() {
-        if let Component::ParentDir = component {
-            return Err(anyhow!("Invalid path: directory traversal is not allowed"));
+/// Longest request line accepted, including the CRLF: Gemini limits URLs to 1024 bytes.
+pub const MAX_REQUEST_LINE: u64 = 1026;
+
+/// Reads a request line, refusing lines longer than `MAX_REQUEST_LINE` and clients that do not send
+/// one within `limit`. Returns the number of bytes read, 0 if the connection was closed.
+pub async fn read_request_line<R: AsyncBufRead + Unpin>(reader: &mut R, line: &mut String, limit: Duration) -> Result<usize> {
+    let bytes_read = tokio::time::timeout(limit, reader.take(MAX_REQUEST_LINE).read_line(line)).await
+        .map_err(|_| anyhow!("Timed out reading the request line"))??;
+    if bytes_read as u64 == MAX_REQUEST_LINE && !line.ends_with('\n') {
+        return Err(anyhow!("Request line exceeds {} bytes", MAX_REQUEST_LINE));
+    }
+    Ok(bytes_read)
+}
+
+/// Watches the directories holding the given files, so that files replaced by rename
+/// (as certbot and editors do) are noticed too. Sends a message for every change to one of the files.
+/// The watcher stops when it is dropped.
+pub fn watch_files(paths: &[&str], changes: mpsc::UnboundedSender<()>) -> notify::Result<RecommendedWatcher> {
+    let watched: Vec<PathBuf> = paths.iter()
+        .map(|path| std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path)))
+        .collect();
+    let file_names: Vec<_> = watched.iter().filter_map(|path| path.file_name().map(|name| name.to_owned())).collect();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            let relevant = !event.kind.is_access()
+                && event.paths.iter().any(|path| path.file_name().is_some_and(|name| file_names.iter().any(|f| f == name)));
+            if relevant {
+                let _ = changes.send(());
+            }
         }
+    })?;
+    let mut directories: Vec<&Path> = watched.iter()
+        .map(|path| path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new(".")))
+        .collect();
+    directories.sort();
+    directories.dedup();
+    for directory in directories {
+        watcher.watch(directory, RecursiveMode::NonRecursive)?;
     }
-    Ok(path.to_string_lossy().to_string())
+    Ok(watcher)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    
-    #[test]
-    fn test_sanitize_valid_path() {
-        let input = "/about";
-        let sanitized = sanitize_path(input).unwrap();
-        assert_eq!(sanitized, "/about");
+/// Listens for SIGHUP. Where the signal is unavailable, `recv` never completes.
+pub struct Hangup {
+    #[cfg(unix)]
+    signal: Option<Signal>,
+}
+
+impl Hangup {
+    pub fn new() -> Self {
+        #[cfg(unix)]
+        {
+            let signal = signal(SignalKind::hangup())
+                .map_err(|e| tracing::warn!("Cannot listen for SIGHUP: {}", e))
+                .ok();
+            Hangup { signal }
+        }
+        #[cfg(not(unix))]
+        Hangup {}
     }
-    
-    #[test]
-    fn test_sanitize_directory_traversal() {
-        let input = "/../secret";
-        assert!(sanitize_path(input).is_err());
+
+    /// Waits for the next SIGHUP.
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = self.signal.as_mut() {
+            signal.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await
     }
 }